@@ -7,11 +7,9 @@
 CREATE DATABASE cnc;
 \c cnc;
 
-drop table 
-
 drop table if exists auth_card_t cascade;
 create table auth_card_t(
-  auth_card_id text not null,
+  auth_card_id text not null primary key,
   creation_time bigint not null,
   creator_user_id bigint not null,
   school_id bigint not null
@@ -36,6 +34,15 @@ create view recent_auth_card_data_v as
   ) maxids
   on maxids.id = acd.auth_card_data_id;
 
+-- The secret each scanner is primed with at manufacture, keyed by the scanner's uid
+-- Scanners prove they hold it at STARTUP, so this is filled in before they are registered
+drop table if exists factory_key_t cascade;
+create table factory_key_t(
+  uid text not null primary key,
+  creation_time bigint not null,
+  secret_key text not null
+);
+
 -- Invariant data about a scanner
 drop table if exists scanner_t cascade;
 create table scanner_t (
@@ -45,6 +52,25 @@ create table scanner_t (
   auth_card_id text not null references auth_card_t(auth_card_id)
 );
 
+-- A credential issued to a scanner, which is separate from the api keys of users
+drop table if exists scanner_key_t cascade;
+create table scanner_key_t(
+  scanner_key_id bigserial primary key,
+  creation_time bigint not null,
+  scanner_id text not null references scanner_t(scanner_id),
+  scanner_key_hash text not null unique,
+  duration bigint not null, -- the key expires this many milliseconds after creation
+  last_used_time bigint not null -- the last time a scanner was connected with the key
+);
+
+-- Once a scanner acknowledges a new key, its older keys are revoked
+drop table if exists scanner_key_revocation_t cascade;
+create table scanner_key_revocation_t(
+  scanner_key_revocation_id bigserial primary key,
+  creation_time bigint not null,
+  scanner_key_id bigint not null unique references scanner_key_t(scanner_key_id)
+);
+
 -- Mutable data about a scanner 
 drop table if exists scanner_data_t cascade;
 create table scanner_data_t(
@@ -52,7 +78,7 @@ create table scanner_data_t(
   creation_time bigint not null,
   creator_user_id bigint not null,
   scanner_id text not null references scanner_t(scanner_id),
-  location_id bigint not null,
+  location_id bigint, -- null until the scanner has been assigned a location
   description text not null,
   active bool not null
 );
@@ -66,9 +92,38 @@ create view recent_scanner_data_v as
   ) maxids
   on maxids.id = sd.scanner_data_id;
 
+-- How a scanner should behave. The scanner_config_id is the version sent to the scanner.
+drop table if exists scanner_config_t cascade;
+create table scanner_config_t(
+  scanner_config_id bigserial primary key,
+  creation_time bigint not null,
+  creator_user_id bigint not null,
+  scanner_id text not null references scanner_t(scanner_id),
+  beep_volume bigint not null, -- percent
+  led_brightness bigint not null, -- percent
+  read_debounce bigint not null -- milliseconds
+);
+
+create view recent_scanner_config_v as
+  select sc.* from scanner_config_t sc
+  inner join (
+   select max(scanner_config_id) id 
+   from scanner_config_t 
+   group by scanner_id
+  ) maxids
+  on maxids.id = sc.scanner_config_id;
+
+-- Every time a scanner applies a config
+drop table if exists scanner_config_ack_t cascade;
+create table scanner_config_ack_t(
+  scanner_config_ack_id bigserial primary key,
+  creation_time bigint not null,
+  scanner_config_id bigint not null references scanner_config_t(scanner_config_id)
+);
+
 -- This cache is calculated every so often, and is used to reduce the cost of expensive queries
 -- place fields for expensive operations that need to access data from the 
-drop table if exists scanner_cache_t;
+drop table if exists scanner_cache_t cascade;
 create table scanner_cache_t(
   scanner_cache_id bigserial primary key,
   creation_time bigint not null,
@@ -79,20 +134,198 @@ create table scanner_cache_t(
   month_uses_count bigint not null -- how many times this scanner has been used in the past 30 days
 );
 
+create view recent_scanner_cache_v as
+  select sc.* from scanner_cache_t sc
+  inner join (
+   select max(scanner_cache_id) id 
+   from scanner_cache_t 
+   group by scanner_id
+  ) maxids
+  on maxids.id = sc.scanner_cache_id;
+
+-- A card read reported by a scanner
+drop table if exists card_read_t cascade;
+create table card_read_t(
+  card_read_id bigserial primary key,
+  creation_time bigint not null, -- when the server received it
+  scanner_id text not null references scanner_t(scanner_id),
+  card_read_epoch bigint not null, -- the readEpoch the scanner sent at STARTUP, or for older scanners how many FULL_RESETs it had acked
+  device_card_read_id bigint not null, -- the cardReadId the scanner assigned
+  card_payload bytea not null,
+  device_time bigint, -- when the scanner says the card was read, null if it didn't say
+  clock_drift_suspect bool not null, -- true if device_time is too far from creation_time to be believed
+  sound_kind bigint, -- IN | OUT | ACK | ERROR | TIMED_OUT, null until decided or if read offline
+  debounced_card_read_id bigint references card_read_t(card_read_id), -- the earlier read of the same card this repeats, null if forwarded
+  offline bool not null -- true if it came in a CARD_READ_BATCH, so the scanner never waited on a sound
+);
+
+-- not unique, since an older scanner can reuse a cardReadId in what the server takes to be the same epoch
+create index card_read_scanner_id_device_card_read_id_i on card_read_t(scanner_id, card_read_epoch, device_card_read_id);
+
+create index card_read_scanner_id_card_payload_i on card_read_t(scanner_id, card_payload);
+create index card_read_scanner_id_creation_time_i on card_read_t(scanner_id, creation_time);
+
+-- A firmware image that can be flashed onto scanners
+drop table if exists firmware_t cascade;
+create table firmware_t(
+  firmware_id bigserial primary key,
+  creation_time bigint not null,
+  creator_user_id bigint not null,
+  firmware_version text not null, -- what scanners report at STARTUP once they run it
+  sha256 text not null, -- hex
+  size bigint not null,
+  firmware_data bytea not null,
+  unique(creator_user_id, firmware_version)
+);
+
 -- This is a command from the server to a scanner
 drop table if exists command_t cascade;
 create table command_t(
-  command_id bigserial,
+  command_id bigserial primary key,
   creation_time bigint not null,
   creator_user_id bigint not null,
   scanner_id text not null references scanner_t(scanner_id),
-  command_kind bigint not null, -- POWER_CYCLE | FULL_RESET | FLASH | BEEP
-); 
+  command_kind bigint not null, -- POWER_CYCLE | FULL_RESET | FLASH | BEEP | UPLOAD_DIAGNOSTICS
+  duration bigint not null, -- if not acked within this many milliseconds, the command expires
+  priority bigint not null, -- queued commands with a higher priority are sent first
+  max_attempts bigint not null, -- how many times the command is sent before giving up
+  firmware_id bigint references firmware_t(firmware_id) -- what to flash, only for FLASH
+);
+
+-- Every time a command is sent down a scanner's websocket
+drop table if exists command_delivery_t cascade;
+create table command_delivery_t(
+  command_delivery_id bigserial primary key,
+  creation_time bigint not null,
+  command_id bigint not null references command_t(command_id)
+);
 
 -- This is the response of a scanner.
-drop table id exists command_ack_t cascade;
-create table command_t_ack(
+drop table if exists command_ack_t cascade;
+create table command_ack_t(
   command_ack_id bigserial primary key,
   creation_time bigint not null,
-  command_id bigint not null references command_t(command_id)
+  command_id bigint not null unique references command_t(command_id)
+);
+
+-- Every time a scanner connects or drops its websocket
+drop table if exists scanner_presence_t cascade;
+create table scanner_presence_t(
+  scanner_presence_id bigserial primary key,
+  creation_time bigint not null,
+  scanner_id text not null references scanner_t(scanner_id),
+  connected bool not null, -- true when the scanner completed STARTUP, false when it dropped
+  protocol_version bigint -- for connects, the version negotiated at STARTUP
+);
+
+create view recent_scanner_presence_v as
+  select sp.* from scanner_presence_t sp
+  inner join (
+   select max(scanner_presence_id) id 
+   from scanner_presence_t 
+   group by scanner_id
+  ) maxids
+  on maxids.id = sp.scanner_presence_id;
+
+-- The progress of a FLASH command on its scanner
+drop table if exists firmware_update_t cascade;
+create table firmware_update_t(
+  firmware_update_id bigserial primary key,
+  creation_time bigint not null,
+  command_id bigint not null references command_t(command_id),
+  firmware_update_kind bigint not null, -- TRANSFERRING | VERIFIED | CORRUPTED | SUCCEEDED | FAILED
+  transfer_offset bigint not null, -- the byte the transfer started or resumed at
+  previous_firmware_version text -- for VERIFIED, what the scanner was running before it flashed
+);
+
+create view recent_firmware_update_v as
+  select fu.* from firmware_update_t fu
+  inner join (
+   select max(firmware_update_id) id 
+   from firmware_update_t 
+   group by command_id
+  ) maxids
+  on maxids.id = fu.firmware_update_id;
+
+-- A plan to flash some firmware onto a user's scanners a stage at a time
+drop table if exists rollout_t cascade;
+create table rollout_t(
+  rollout_id bigserial primary key,
+  creation_time bigint not null,
+  creator_user_id bigint not null, -- only this user's scanners are flashed
+  firmware_id bigint not null references firmware_t(firmware_id),
+  location_id bigint, -- if set, only scanners at this location are flashed
+  stage_percentages bigint[] not null, -- how much of the scanners have been flashed by the end of each stage
+  failure_threshold_percent bigint not null -- the rollout halts once more than this many of the flashes have failed
+);
+
+-- Mutable data about a rollout
+drop table if exists rollout_data_t cascade;
+create table rollout_data_t(
+  rollout_data_id bigserial primary key,
+  creation_time bigint not null,
+  rollout_id bigint not null references rollout_t(rollout_id),
+  stage bigint not null, -- index into stage_percentages
+  rollout_status_kind bigint not null -- RUNNING | HALTED | COMPLETED
+);
+
+create view recent_rollout_data_v as
+  select rd.* from rollout_data_t rd
+  inner join (
+   select max(rollout_data_id) id 
+   from rollout_data_t 
+   group by rollout_id
+  ) maxids
+  on maxids.id = rd.rollout_data_id;
+
+-- Health reports from scanners. Old reports are merged into hourly and then daily averages,
+-- so each row stands for sample_count reports made over resolution milliseconds.
+drop table if exists telemetry_t cascade;
+create table telemetry_t(
+  telemetry_id bigserial primary key,
+  creation_time bigint not null, -- the start of the period for merged rows
+  scanner_id text not null references scanner_t(scanner_id),
+  resolution bigint not null, -- 0 for reports as they were sent
+  sample_count bigint not null,
+  uptime bigint not null, -- seconds, the most the scanner reported
+  free_heap bigint not null, -- bytes, averaged
+  wifi_rssi bigint not null, -- dBm, averaged
+  temperature double precision not null, -- degrees celsius, averaged
+  reader_errors bigint not null -- summed
+);
+
+create index telemetry_scanner_id_creation_time_i on telemetry_t(scanner_id, creation_time);
+
+-- Log lines sent by scanners
+drop table if exists scanner_log_t cascade;
+create table scanner_log_t(
+  scanner_log_id bigserial primary key,
+  creation_time bigint not null,
+  scanner_id text not null references scanner_t(scanner_id),
+  severity_kind bigint not null, -- INFO | WARNING | ERROR | FATAL
+  msg text not null
+);
+
+create index scanner_log_scanner_id_creation_time_i on scanner_log_t(scanner_id, creation_time);
+
+-- The parts of an UPLOAD_DIAGNOSTICS response that have arrived so far
+drop table if exists diagnostics_part_t cascade;
+create table diagnostics_part_t(
+  diagnostics_part_id bigserial primary key,
+  creation_time bigint not null,
+  command_id bigint not null references command_t(command_id),
+  part_index bigint not null,
+  part_count bigint not null,
+  part_data bytea not null,
+  unique(command_id, part_index)
+);
+
+-- An UPLOAD_DIAGNOSTICS response, once every part has arrived
+drop table if exists diagnostics_t cascade;
+create table diagnostics_t(
+  diagnostics_id bigserial primary key,
+  creation_time bigint not null,
+  command_id bigint not null unique references command_t(command_id),
+  scanner_id text not null references scanner_t(scanner_id),
+  diagnostics_data bytea not null
 );
@@ -1,9 +1,14 @@
+use super::feed_socket;
 use super::handlers;
+use super::scanner_socket;
 use super::utils;
 use super::Config;
+use super::Connections;
 use super::Db;
+use super::Feed;
 use super::SERVICE_NAME;
 use auth_service_api::response::AuthError;
+use cnc_service_api::response::CncError;
 use mail_service_api::client::MailService;
 use std::collections::HashMap;
 use std::convert::Infallible;
@@ -27,6 +32,8 @@ pub fn api(
   config: Config,
   db: Db,
   mail_service: MailService,
+  connections: Connections,
+  feed: Feed,
 ) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
   // public API
   api_info()
@@ -35,6 +42,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "verification_challenge" / "new"),
         handlers::verification_challenge_new,
       ),
@@ -42,6 +51,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "api_key" / "new_valid"),
         handlers::api_key_new_valid,
       ),
@@ -49,6 +60,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "api_key" / "new_cancel"),
         handlers::api_key_new_cancel,
       ),
@@ -56,6 +69,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "user" / "new"),
         handlers::user_new,
       ),
@@ -63,6 +78,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "user_data" / "new"),
         handlers::user_data_new,
       ),
@@ -70,6 +87,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "email" / "new"),
         handlers::email_new,
       ),
@@ -77,6 +96,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "parent_permission" / "new"),
         handlers::parent_permission_new,
       ),
@@ -84,6 +105,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "password_reset" / "new"),
         handlers::password_reset_new,
       ),
@@ -91,6 +114,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "password" / "new_reset"),
         handlers::password_new_reset,
       ),
@@ -98,6 +123,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "password" / "new_change"),
         handlers::password_new_change,
       ),
@@ -105,6 +132,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "user" / "view"),
         handlers::user_view,
       ),
@@ -112,6 +141,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "user_data" / "view"),
         handlers::user_data_view,
       ),
@@ -119,6 +150,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "password" / "view"),
         handlers::password_view,
       ),
@@ -126,6 +159,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "email" / "view"),
         handlers::email_view,
       ),
@@ -133,6 +168,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "parent_permission" / "view"),
         handlers::parent_permission_view,
       ),
@@ -140,6 +177,8 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "verification_challenge" / "view"),
         handlers::verification_challenge_view,
       ),
@@ -147,14 +186,240 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("public" / "api_key" / "view"),
         handlers::api_key_view,
       ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "register"),
+        handlers::register,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "command" / "new"),
+        handlers::command_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "command" / "view"),
+        handlers::command_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_stats" / "view"),
+        handlers::scanner_stats_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_presence" / "view"),
+        handlers::scanner_presence_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_uptime" / "view"),
+        handlers::scanner_uptime_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_config" / "new"),
+        handlers::scanner_config_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_config" / "view"),
+        handlers::scanner_config_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "telemetry" / "view"),
+        handlers::telemetry_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_log" / "view"),
+        handlers::scanner_log_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "diagnostics" / "view"),
+        handlers::diagnostics_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "diagnostics" / "download"),
+        handlers::diagnostics_download,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_key" / "rotate"),
+        handlers::scanner_key_rotate,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "scanner_key" / "view"),
+        handlers::scanner_key_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "firmware" / "new"),
+        handlers::firmware_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "firmware" / "view"),
+        handlers::firmware_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "firmware_update" / "view"),
+        handlers::firmware_update_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "rollout" / "new"),
+        handlers::rollout_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "rollout_data" / "new"),
+        handlers::rollout_data_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "rollout" / "view"),
+        handlers::rollout_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "auth_card" / "new"),
+        handlers::auth_card_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "auth_card_data" / "new"),
+        handlers::auth_card_data_new,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "auth_card" / "view"),
+        handlers::auth_card_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("public" / "auth_card_data" / "view"),
+        handlers::auth_card_data_view,
+      ),
+      scanner_socket_filter(
+        config.clone(),
+        db.clone(),
+        connections.clone(),
+        feed.clone()
+      ),
       // Private API (note that there's no "public" at the beginning, so nginx won't expose it)
       adapter(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("get_user_by_id"),
         handlers::get_user_by_id,
       ),
@@ -162,9 +427,39 @@ pub fn api(
         config.clone(),
         db.clone(),
         mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
         warp::path!("get_user_by_api_key_if_valid"),
         handlers::get_user_by_api_key_if_valid,
-      )
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("command"),
+        handlers::command,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("device" / "view"),
+        handlers::device_view,
+      ),
+      adapter(
+        config.clone(),
+        db.clone(),
+        mail_service.clone(),
+        connections.clone(),
+        feed.clone(),
+        warp::path!("factory_key" / "new"),
+        handlers::factory_key_new,
+      ),
+      feed_socket_filter(feed.clone())
     ))
     .recover(handle_rejection)
 }
@@ -176,34 +471,68 @@ fn api_info() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection
   warp::path!("info").map(move || warp::reply::json(&info))
 }
 
+// lets you pass in an arbitrary parameter
+fn with<T: Clone + Send>(t: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone {
+  warp::any().map(move || t.clone())
+}
+
+// upgrades requests to a websocket session with a scanner
+fn scanner_socket_filter(
+  config: Config,
+  db: Db,
+  connections: Connections,
+  feed: Feed,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+  warp::path!("public" / "websocket")
+    .and(warp::ws())
+    .and(with(config))
+    .and(with(db))
+    .and(with(connections))
+    .and(with(feed))
+    .map(|ws: warp::ws::Ws, config, db, connections, feed| {
+      ws.on_upgrade(move |socket| scanner_socket::handle(socket, config, db, connections, feed))
+    })
+}
+
+// upgrades requests to a websocket session with a listening microservice
+// (note that there's no "public" at the beginning, so nginx won't expose it)
+fn feed_socket_filter(
+  feed: Feed,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+  warp::path!("feed")
+    .and(warp::ws())
+    .and(with(feed))
+    .map(|ws: warp::ws::Ws, feed| ws.on_upgrade(move |socket| feed_socket::handle(socket, feed)))
+}
+
 // this function adapts a handler function to a warp filter
 // it accepts an initial path filter
-fn adapter<PropsType, ResponseType, F>(
+fn adapter<PropsType, ResponseType, ErrorType, F>(
   config: Config,
   db: Db,
   mail_service: MailService,
+  connections: Connections,
+  feed: Feed,
   filter: impl Filter<Extract = (), Error = warp::Rejection> + Clone,
-  handler: fn(Config, Db, MailService, PropsType) -> F,
+  handler: fn(Config, Db, MailService, Connections, Feed, PropsType) -> F,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
-  F: Future<Output = Result<ResponseType, AuthError>> + Send,
+  F: Future<Output = Result<ResponseType, ErrorType>> + Send,
+  ErrorType: HandlerError,
   PropsType: Send + serde::de::DeserializeOwned,
   ResponseType: Send + serde::ser::Serialize,
 {
-  // lets you pass in an arbitrary parameter
-  fn with<T: Clone + Send>(t: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone {
-    warp::any().map(move || t.clone())
-  }
-
   filter
     .and(with(config))
     .and(with(db))
     .and(with(mail_service))
+    .and(with(connections))
+    .and(with(feed))
     .and(warp::body::json())
-    .and_then(async move |config, db, mail_service, props| {
-      handler(config, db, mail_service, props)
+    .and_then(async move |config, db, mail_service, connections, feed, props| {
+      handler(config, db, mail_service, connections, feed, props)
         .await
-        .map_err(auth_error)
+        .map_err(HandlerError::into_rejection)
     })
     .map(|x| warp::reply::json(&Ok::<ResponseType, ()>(x)))
 }
@@ -229,6 +558,11 @@ async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infa
   } else if let Some(AuthErrorRejection(auth_error)) = err.find() {
     code = StatusCode::BAD_REQUEST;
     message = auth_error.clone();
+  } else if let Some(CncErrorRejection(cnc_error)) = err.find() {
+    return Ok(warp::reply::with_status(
+      warp::reply::json(&Err::<(), CncError>(cnc_error.clone())),
+      StatusCode::BAD_REQUEST,
+    ));
   } else {
     // We should have expected this... Just log and say its a 500
     utils::log(utils::Event {
@@ -252,6 +586,23 @@ async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, Infa
 pub struct AuthErrorRejection(pub AuthError);
 impl warp::reject::Reject for AuthErrorRejection {}
 
-fn auth_error(auth_error: AuthError) -> warp::reject::Rejection {
-  warp::reject::custom(AuthErrorRejection(auth_error))
+#[derive(Debug)]
+pub struct CncErrorRejection(pub CncError);
+impl warp::reject::Reject for CncErrorRejection {}
+
+// the error types that handlers may return
+pub trait HandlerError: Send {
+  fn into_rejection(self) -> warp::reject::Rejection;
+}
+
+impl HandlerError for AuthError {
+  fn into_rejection(self) -> warp::reject::Rejection {
+    warp::reject::custom(AuthErrorRejection(self))
+  }
+}
+
+impl HandlerError for CncError {
+  fn into_rejection(self) -> warp::reject::Rejection {
+    warp::reject::custom(CncErrorRejection(self))
+  }
 }
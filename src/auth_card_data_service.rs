@@ -0,0 +1,140 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for AuthCardData {
+  // select * from auth_card_data order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> AuthCardData {
+    AuthCardData {
+      auth_card_data_id: row.get("auth_card_data_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      auth_card_id: row.get("auth_card_id"),
+      description: row.get("description"),
+      active: row.get("active"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  auth_card_id: String,
+  description: String,
+  active: bool,
+) -> Result<AuthCardData, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let auth_card_data_id = con
+    .query_one(
+      "INSERT INTO
+       auth_card_data_t(
+        creation_time,
+        creator_user_id,
+        auth_card_id,
+        description,
+        active
+       )
+       VALUES($1, $2, $3, $4, $5)
+       RETURNING auth_card_data_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &auth_card_id,
+        &description,
+        &active,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return auth card data
+  Ok(AuthCardData {
+    auth_card_data_id,
+    creation_time,
+    creator_user_id,
+    auth_card_id,
+    description,
+    active,
+  })
+}
+
+// gets most recent auth card data by auth_card_id
+pub async fn get_by_auth_card_id(
+  con: &mut impl GenericClient,
+  auth_card_id: &str,
+) -> Result<Option<AuthCardData>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM recent_auth_card_data_v WHERE auth_card_id=$1",
+      &[&auth_card_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+#[allow(unused)]
+pub async fn get_by_auth_card_data_id(
+  con: &mut impl GenericClient,
+  auth_card_data_id: i64,
+) -> Result<Option<AuthCardData>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM auth_card_data_t WHERE auth_card_data_id=$1",
+      &[&auth_card_data_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// only returns the history of auth cards added by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::AuthCardDataViewProps,
+) -> Result<Vec<AuthCardData>, tokio_postgres::Error> {
+  let sql = [
+    if props.only_recent {
+      "SELECT acd.* FROM recent_auth_card_data_v acd"
+    } else {
+      "SELECT acd.* FROM auth_card_data_t acd"
+    },
+    " INNER JOIN auth_card_t ac ON ac.auth_card_id = acd.auth_card_id",
+    " WHERE 1 = 1",
+    " AND ($1::bigint[] IS NULL OR acd.auth_card_data_id = ANY($1))",
+    " AND ($2::bigint   IS NULL OR acd.creation_time >= $2)",
+    " AND ($3::bigint   IS NULL OR acd.creation_time <= $3)",
+    " AND ($4::bigint[] IS NULL OR acd.creator_user_id = ANY($4))",
+    " AND ($5::text[]   IS NULL OR acd.auth_card_id = ANY($5))",
+    " AND ($6::bool     IS NULL OR acd.active = $6)",
+    " AND ac.creator_user_id = $7",
+    " ORDER BY acd.auth_card_data_id",
+  ]
+  .join("\n");
+
+  let stmnt = con.prepare(&sql).await?;
+
+  let results = con
+    .query(
+      &stmnt,
+      &[
+        &props.auth_card_data_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.auth_card_id,
+        &props.active,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
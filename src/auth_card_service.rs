@@ -0,0 +1,99 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for AuthCard {
+  // select * from auth_card order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> AuthCard {
+    AuthCard {
+      auth_card_id: row.get("auth_card_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      school_id: row.get("school_id"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  auth_card_id: String,
+  creator_user_id: i64,
+  school_id: i64,
+) -> Result<AuthCard, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  con
+    .execute(
+      "INSERT INTO
+       auth_card_t(
+        auth_card_id,
+        creation_time,
+        creator_user_id,
+        school_id
+       )
+       VALUES($1, $2, $3, $4)
+      ",
+      &[&auth_card_id, &creation_time, &creator_user_id, &school_id],
+    )
+    .await?;
+
+  // return auth card
+  Ok(AuthCard {
+    auth_card_id,
+    creation_time,
+    creator_user_id,
+    school_id,
+  })
+}
+
+pub async fn get_by_auth_card_id(
+  con: &mut impl GenericClient,
+  auth_card_id: &str,
+) -> Result<Option<AuthCard>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM auth_card_t WHERE auth_card_id=$1",
+      &[&auth_card_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// only returns auth cards added by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::AuthCardViewProps,
+) -> Result<Vec<AuthCard>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT ac.* FROM auth_card_t ac
+       INNER JOIN recent_auth_card_data_v acd ON acd.auth_card_id = ac.auth_card_id
+       WHERE 1 = 1
+       AND ($1::text[]   IS NULL OR ac.auth_card_id = ANY($1))
+       AND ($2::bigint   IS NULL OR ac.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR ac.creation_time <= $3)
+       AND ($4::bigint[] IS NULL OR ac.creator_user_id = ANY($4))
+       AND ($5::bigint[] IS NULL OR ac.school_id = ANY($5))
+       AND ($6::bool     IS NULL OR acd.active = $6)
+       AND ac.creator_user_id = $7
+       ORDER BY ac.creation_time
+      ",
+      &[
+        &props.auth_card_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.school_id,
+        &props.active,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,196 @@
+use super::db_types::*;
+use super::device_protocol::SoundKind;
+use super::utils::current_time_millis;
+use std::convert::TryInto;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for CardRead {
+  // select * from card_read order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> CardRead {
+    CardRead {
+      card_read_id: row.get("card_read_id"),
+      creation_time: row.get("creation_time"),
+      scanner_id: row.get("scanner_id"),
+      card_read_epoch: row.get("card_read_epoch"),
+      device_card_read_id: row.get("device_card_read_id"),
+      card_payload: row.get("card_payload"),
+      device_time: row.get("device_time"),
+      clock_drift_suspect: row.get("clock_drift_suspect"),
+      // means that there's a mismatch between the values of the enum and the value stored in the column
+      sound_kind: row
+        .get::<&str, Option<i64>>("sound_kind")
+        .map(|x| (x as u8).try_into().unwrap()),
+      debounced_card_read_id: row.get("debounced_card_read_id"),
+      offline: row.get("offline"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_id: String,
+  card_read_epoch: i64,
+  device_card_read_id: i64,
+  card_payload: Vec<u8>,
+  device_time: Option<i64>,
+  clock_drift_suspect: bool,
+  debounced_card_read_id: Option<i64>,
+  offline: bool,
+) -> Result<CardRead, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let card_read_id = con
+    .query_one(
+      "INSERT INTO
+       card_read_t(
+        creation_time,
+        scanner_id,
+        card_read_epoch,
+        device_card_read_id,
+        card_payload,
+        device_time,
+        clock_drift_suspect,
+        debounced_card_read_id,
+        offline
+       )
+       VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)
+       RETURNING card_read_id
+      ",
+      &[
+        &creation_time,
+        &scanner_id,
+        &card_read_epoch,
+        &device_card_read_id,
+        &card_payload,
+        &device_time,
+        &clock_drift_suspect,
+        &debounced_card_read_id,
+        &offline,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return card read
+  Ok(CardRead {
+    card_read_id,
+    creation_time,
+    scanner_id,
+    card_read_epoch,
+    device_card_read_id,
+    card_payload,
+    device_time,
+    clock_drift_suspect,
+    sound_kind: None,
+    debounced_card_read_id,
+    offline,
+  })
+}
+
+// records the sound the listeners decided on
+pub async fn set_sound_kind(
+  con: &mut impl GenericClient,
+  card_read_id: i64,
+  sound_kind: SoundKind,
+) -> Result<(), tokio_postgres::Error> {
+  con
+    .execute(
+      "UPDATE card_read_t SET sound_kind = $1 WHERE card_read_id = $2",
+      &[&(sound_kind as i64), &card_read_id],
+    )
+    .await?;
+
+  Ok(())
+}
+
+pub async fn get_by_device_card_read_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  card_read_epoch: i64,
+  device_card_read_id: i64,
+) -> Result<Option<CardRead>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM card_read_t
+       WHERE scanner_id = $1
+       AND card_read_epoch = $2
+       AND device_card_read_id = $3
+       ORDER BY card_read_id DESC
+       LIMIT 1
+      ",
+      &[&scanner_id, &card_read_epoch, &device_card_read_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// the newest read of the card at the scanner that was forwarded to the feed
+pub async fn get_latest_forwarded_by_card_payload(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  card_payload: &[u8],
+) -> Result<Option<CardRead>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM card_read_t
+       WHERE scanner_id = $1
+       AND card_payload = $2
+       AND debounced_card_read_id IS NULL
+       ORDER BY card_read_id DESC
+       LIMIT 1
+      ",
+      &[&scanner_id, &card_payload],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+pub async fn get_latest_time_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<Option<i64>, tokio_postgres::Error> {
+  let time = con
+    .query_one(
+      "SELECT MAX(creation_time) FROM card_read_t WHERE scanner_id=$1",
+      &[&scanner_id],
+    )
+    .await?
+    .get(0);
+
+  Ok(time)
+}
+
+// How many times every scanner has ever been used, and how many times since min_creation_time.
+// Repeat taps aren't uses, and resent reads are only ever stored once.
+pub async fn count_all_by_scanner_id(
+  con: &mut impl GenericClient,
+  min_creation_time: i64,
+) -> Result<Vec<ScannerUsage>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT
+        s.scanner_id,
+        s.creator_user_id,
+        count(cr.card_read_id) lifetime_uses_count,
+        count(cr.card_read_id) FILTER (WHERE cr.creation_time >= $1) month_uses_count
+       FROM scanner_t s
+       LEFT JOIN card_read_t cr ON cr.scanner_id = s.scanner_id AND cr.debounced_card_read_id IS NULL
+       GROUP BY s.scanner_id, s.creator_user_id
+      ",
+      &[&min_creation_time],
+    )
+    .await?
+    .into_iter()
+    .map(|row| ScannerUsage {
+      scanner_id: row.get("scanner_id"),
+      creator_user_id: row.get("creator_user_id"),
+      lifetime_uses_count: row.get("lifetime_uses_count"),
+      month_uses_count: row.get("month_uses_count"),
+    })
+    .collect();
+  Ok(results)
+}
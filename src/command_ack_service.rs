@@ -0,0 +1,58 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for CommandAck {
+  // select * from command_ack order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> CommandAck {
+    CommandAck {
+      command_ack_id: row.get("command_ack_id"),
+      creation_time: row.get("creation_time"),
+      command_id: row.get("command_id"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<CommandAck, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let command_ack_id = con
+    .query_one(
+      "INSERT INTO
+       command_ack_t(
+        creation_time,
+        command_id
+       )
+       VALUES($1, $2)
+       RETURNING command_ack_id
+      ",
+      &[&creation_time, &command_id],
+    )
+    .await?
+    .get(0);
+
+  // return command ack
+  Ok(CommandAck {
+    command_ack_id,
+    creation_time,
+    command_id,
+  })
+}
+
+pub async fn get_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<Option<CommandAck>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM command_ack_t WHERE command_id=$1",
+      &[&command_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
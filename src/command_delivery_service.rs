@@ -0,0 +1,46 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<CommandDelivery, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let command_delivery_id = con
+    .query_one(
+      "INSERT INTO
+       command_delivery_t(
+        creation_time,
+        command_id
+       )
+       VALUES($1, $2)
+       RETURNING command_delivery_id
+      ",
+      &[&creation_time, &command_id],
+    )
+    .await?
+    .get(0);
+
+  // return command delivery
+  Ok(CommandDelivery {
+    command_delivery_id,
+    creation_time,
+    command_id,
+  })
+}
+
+pub async fn count_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<i64, tokio_postgres::Error> {
+  let count: i64 = con
+    .query_one(
+      "SELECT count(*) FROM command_delivery_t WHERE command_id=$1",
+      &[&command_id],
+    )
+    .await?
+    .get(0);
+  Ok(count)
+}
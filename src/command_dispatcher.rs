@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio_postgres::GenericClient;
+
+use super::command_delivery_service;
+use super::command_policy::CommandPolicies;
+use super::command_service;
+use super::db_types::Command;
+use super::device_protocol;
+use super::device_protocol::{FirmwareManifest, ServerMessage};
+use super::firmware_service;
+use super::utils;
+use super::Config;
+use super::Connections;
+use super::Db;
+
+fn report_postgres_err(e: tokio_postgres::Error) {
+  utils::log(utils::Event {
+    msg: e.to_string(),
+    source: e.source().map(|e| e.to_string()),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+// Pushes the command down the scanner's websocket if the scanner is connected and can run it,
+// recording the delivery. Returns whether the command was sent.
+async fn dispatch(
+  con: &mut impl GenericClient,
+  connections: &Connections,
+  command: &Command,
+) -> Result<bool, tokio_postgres::Error> {
+  let firmware = match command.firmware_id {
+    Some(firmware_id) => firmware_service::get_by_firmware_id(con, firmware_id)
+      .await?
+      .map(|x| FirmwareManifest {
+        firmware_id: x.firmware_id,
+        firmware_version: x.firmware_version,
+        size: x.size,
+        sha256: x.sha256,
+      }),
+    None => None,
+  };
+
+  // scanners that can't run this kind of command would fail to parse it
+  let sent = match connections.lock().await.get(&command.scanner_id) {
+    Some(connection)
+      if device_protocol::command_allowed(&command.command_kind, connection.protocol_version) =>
+    {
+      connection
+        .sender
+        .send(ServerMessage::Command {
+          command_id: command.command_id,
+          command_kind: command.command_kind.clone(),
+          firmware,
+        })
+        .is_ok()
+    }
+    _ => false,
+  };
+
+  if sent {
+    command_delivery_service::add(con, command.command_id).await?;
+  }
+
+  Ok(sent)
+}
+
+// registers interest in a command's ack, returns None if the scanner isn't connected
+async fn expect_ack(
+  connections: &Connections,
+  scanner_id: &str,
+  command_id: i64,
+) -> Option<oneshot::Receiver<()>> {
+  let (sender, receiver) = oneshot::channel();
+  connections
+    .lock()
+    .await
+    .get_mut(scanner_id)?
+    .pending_acks
+    .entry(command_id)
+    .or_insert_with(Vec::new)
+    .push(sender);
+  Some(receiver)
+}
+
+// drops the senders of whoever stopped waiting on the command's ack
+async fn forget_ack(connections: &Connections, scanner_id: &str, command_id: i64) {
+  if let Some(connection) = connections.lock().await.get_mut(scanner_id) {
+    if let Some(pending) = connection.pending_acks.get_mut(&command_id) {
+      pending.retain(|x| !x.is_closed());
+      if pending.is_empty() {
+        connection.pending_acks.remove(&command_id);
+      }
+    }
+  }
+}
+
+// Runs alongside a scanner's session, delivering its queued commands one at a time:
+// highest priority first, then oldest first. Commands are resent until they're acked,
+// expire, or run out of attempts. Stops once the session is closed or replaced.
+pub async fn run(
+  config: Config,
+  db: Db,
+  connections: Connections,
+  scanner_id: String,
+  connection_id: u64,
+  wakeup: Arc<Notify>,
+) {
+  loop {
+    let protocol_version = match connections.lock().await.get(&scanner_id) {
+      Some(x) if x.connection_id == connection_id => x.protocol_version,
+      _ => break,
+    };
+
+    // commands this scanner can't run stay queued for when it starts up with newer firmware
+    let next = command_service::get_next_pending_by_scanner_id(
+      &mut *db.lock().await,
+      &scanner_id,
+      &device_protocol::command_kinds(protocol_version),
+    )
+    .await;
+
+    let command = match next {
+      Ok(Some(command)) => command,
+      // sleep until a command is queued or the session ends
+      Ok(None) => {
+        wakeup.notified().await;
+        continue;
+      }
+      Err(e) => {
+        report_postgres_err(e);
+        wakeup.notified().await;
+        continue;
+      }
+    };
+
+    // listen before sending, so that a quick ack can't be missed
+    let receiver = match expect_ack(&connections, &scanner_id, command.command_id).await {
+      Some(receiver) => receiver,
+      None => break,
+    };
+
+    match dispatch(&mut *db.lock().await, &connections, &command).await {
+      Ok(true) => (),
+      Ok(false) => break,
+      Err(e) => {
+        report_postgres_err(e);
+        break;
+      }
+    }
+
+    let ack_timeout = config.command_policies.get(&command.command_kind).ack_timeout;
+
+    // if it isn't acked in time, the next pass around the loop resends it
+    if tokio::time::timeout(Duration::from_millis(ack_timeout as u64), receiver).await.is_err() {
+      forget_ack(&connections, &scanner_id, command.command_id).await;
+    }
+  }
+}
+
+// lets the scanner's delivery loop know that there's a new command queued
+pub async fn wake(connections: &Connections, scanner_id: &str) {
+  if let Some(connection) = connections.lock().await.get(scanner_id) {
+    connection.wakeup.notify_one();
+  }
+}
+
+// How long a command may take to be acked. Every command queued ahead of it may use up
+// all of its attempts first, but there's no point waiting past the command's expiry.
+fn ack_deadline(
+  command_policies: &CommandPolicies,
+  queue: &[Command],
+  command: &Command,
+  now: i64,
+) -> Duration {
+  let wait: i64 = queue
+    .iter()
+    .take_while(|x| x.command_id != command.command_id)
+    .chain(std::iter::once(command))
+    .map(|x| command_policies.get(&x.command_kind).ack_timeout * x.max_attempts)
+    .sum();
+
+  let expiry = command.creation_time + command.duration - now;
+
+  Duration::from_millis(wait.min(expiry).max(0) as u64)
+}
+
+// Hands a newly queued command to the scanner's delivery loop and waits for it to be acked,
+// across all of its attempts. Returns false if the scanner is offline or never acks.
+pub async fn deliver_and_wait(
+  config: &Config,
+  db: &Db,
+  connections: &Connections,
+  command: &Command,
+) -> bool {
+  let protocol_version = match connections.lock().await.get(&command.scanner_id) {
+    Some(x) => x.protocol_version,
+    None => return false,
+  };
+
+  let receiver = match expect_ack(connections, &command.scanner_id, command.command_id).await {
+    Some(receiver) => receiver,
+    None => return false,
+  };
+
+  let queue = command_service::get_all_pending_by_scanner_id(
+    &mut *db.lock().await,
+    &command.scanner_id,
+    &device_protocol::command_kinds(protocol_version),
+  )
+  .await
+    .unwrap_or_else(|e| {
+      report_postgres_err(e);
+      vec![]
+    });
+
+  wake(connections, &command.scanner_id).await;
+
+  let deadline = ack_deadline(&config.command_policies, &queue, command, utils::current_time_millis());
+
+  match tokio::time::timeout(deadline, receiver).await {
+    Ok(result) => result.is_ok(),
+    Err(_) => {
+      forget_ack(connections, &command.scanner_id, command.command_id).await;
+      false
+    }
+  }
+}
+
+// wakes up everyone waiting on this command's ack
+pub async fn resolve_ack(connections: &Connections, scanner_id: &str, command_id: i64) {
+  if let Some(connection) = connections.lock().await.get_mut(scanner_id) {
+    for pending in connection.pending_acks.remove(&command_id).unwrap_or_default() {
+      let _ = pending.send(());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use cnc_service_api::request::CommandKind;
+
+  fn command(command_id: i64, command_kind: CommandKind) -> Command {
+    Command {
+      command_id,
+      creation_time: 0,
+      creator_user_id: 1,
+      scanner_id: "scanner".to_owned(),
+      command_kind,
+      duration: 24 * 60 * 60 * 1000,
+      priority: 0,
+      max_attempts: 2,
+      firmware_id: None,
+    }
+  }
+
+  #[test]
+  fn ack_deadline_with_nothing_ahead_is_its_own_attempts() {
+    let command_policies = CommandPolicies::default();
+    let beep = command(1, CommandKind::Beep);
+    let ack_timeout = command_policies.beep.ack_timeout;
+    assert_eq!(
+      ack_deadline(&command_policies, &[beep.clone()], &beep, 0),
+      Duration::from_millis((ack_timeout * 2) as u64)
+    );
+  }
+
+  #[test]
+  fn ack_deadline_waits_for_the_commands_ahead() {
+    let command_policies = CommandPolicies::default();
+    let flash = command(1, CommandKind::Flash);
+    let beep = command(2, CommandKind::Beep);
+    let wait = (command_policies.flash.ack_timeout + command_policies.beep.ack_timeout) * 2;
+    assert_eq!(
+      ack_deadline(&command_policies, &[flash, beep.clone()], &beep, 0),
+      Duration::from_millis(wait as u64)
+    );
+  }
+
+  #[test]
+  fn ack_deadline_stops_at_expiry() {
+    let command_policies = CommandPolicies::default();
+    let mut beep = command(1, CommandKind::Beep);
+    beep.duration = 100;
+    assert_eq!(
+      ack_deadline(&command_policies, &[beep.clone()], &beep, 50),
+      Duration::from_millis(50)
+    );
+    assert_eq!(
+      ack_deadline(&command_policies, &[beep.clone()], &beep, 500),
+      Duration::from_millis(0)
+    );
+  }
+}
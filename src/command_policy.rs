@@ -0,0 +1,141 @@
+use cnc_service_api::request::CommandKind;
+use serde::{Deserialize, Serialize};
+
+// How a kind of command is delivered to a scanner.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandPolicy {
+  // how many times the command is sent before giving up
+  pub max_attempts: i64,
+  // how many milliseconds to wait for a COMMAND_ACK before resending
+  pub ack_timeout: i64,
+  // queued commands with a higher priority are sent first
+  pub priority: i64,
+}
+
+// One policy per command kind, optionally loaded from a json file at startup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct CommandPolicies {
+  pub power_cycle: CommandPolicy,
+  pub full_reset: CommandPolicy,
+  pub flash: CommandPolicy,
+  pub beep: CommandPolicy,
+  // policy files written before this kind existed leave it out
+  #[serde(default = "default_upload_diagnostics")]
+  pub upload_diagnostics: CommandPolicy,
+}
+
+fn default_upload_diagnostics() -> CommandPolicy {
+  CommandPolicy {
+    max_attempts: 3,
+    ack_timeout: 10000,
+    priority: 0,
+  }
+}
+
+impl CommandPolicies {
+  // parses and checks a policy file
+  pub fn parse(json: &str) -> Result<CommandPolicies, String> {
+    let command_policies: CommandPolicies =
+      serde_json::from_str(json).map_err(|e| format!("malformed command policy file: {}", e))?;
+
+    for (name, policy) in [
+      ("POWER_CYCLE", &command_policies.power_cycle),
+      ("FULL_RESET", &command_policies.full_reset),
+      ("FLASH", &command_policies.flash),
+      ("BEEP", &command_policies.beep),
+      ("UPLOAD_DIAGNOSTICS", &command_policies.upload_diagnostics),
+    ] {
+      if policy.max_attempts < 1 || policy.ack_timeout < 1 {
+        return Err(format!(
+          "{} needs at least one attempt and a positive ack timeout",
+          name
+        ));
+      }
+    }
+
+    Ok(command_policies)
+  }
+
+  pub fn get(&self, command_kind: &CommandKind) -> &CommandPolicy {
+    match command_kind {
+      CommandKind::PowerCycle => &self.power_cycle,
+      CommandKind::FullReset => &self.full_reset,
+      CommandKind::Flash => &self.flash,
+      CommandKind::Beep => &self.beep,
+      CommandKind::UploadDiagnostics => &self.upload_diagnostics,
+    }
+  }
+}
+
+impl Default for CommandPolicies {
+  fn default() -> CommandPolicies {
+    CommandPolicies {
+      power_cycle: CommandPolicy {
+        max_attempts: 3,
+        ack_timeout: 5000,
+        priority: 1,
+      },
+      full_reset: CommandPolicy {
+        max_attempts: 5,
+        ack_timeout: 5000,
+        priority: 2,
+      },
+      flash: CommandPolicy {
+        max_attempts: 3,
+        ack_timeout: 30000,
+        priority: 0,
+      },
+      beep: CommandPolicy {
+        max_attempts: 2,
+        ack_timeout: 2000,
+        priority: 0,
+      },
+      upload_diagnostics: default_upload_diagnostics(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_accepts_the_defaults() {
+    let json = serde_json::to_string(&CommandPolicies::default()).unwrap();
+    assert!(CommandPolicies::parse(&json).is_ok());
+  }
+
+  #[test]
+  fn parse_rejects_negative_ack_timeout() {
+    let mut command_policies = CommandPolicies::default();
+    command_policies.beep.ack_timeout = -1;
+    let json = serde_json::to_string(&command_policies).unwrap();
+    assert!(CommandPolicies::parse(&json).is_err());
+  }
+
+  #[test]
+  fn parse_rejects_zero_attempts() {
+    let mut command_policies = CommandPolicies::default();
+    command_policies.flash.max_attempts = 0;
+    let json = serde_json::to_string(&command_policies).unwrap();
+    assert!(CommandPolicies::parse(&json).is_err());
+  }
+
+  #[test]
+  fn parse_rejects_malformed_json() {
+    assert!(CommandPolicies::parse("{").is_err());
+  }
+
+  #[test]
+  fn parse_defaults_a_missing_upload_diagnostics() {
+    let mut json = serde_json::to_value(&CommandPolicies::default()).unwrap();
+    json.as_object_mut().unwrap().remove("UPLOAD_DIAGNOSTICS");
+    let command_policies = CommandPolicies::parse(&json.to_string()).unwrap();
+    let default = default_upload_diagnostics();
+    assert_eq!(command_policies.upload_diagnostics.max_attempts, default.max_attempts);
+    assert_eq!(command_policies.upload_diagnostics.ack_timeout, default.ack_timeout);
+    assert_eq!(command_policies.upload_diagnostics.priority, default.priority);
+  }
+}
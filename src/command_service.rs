@@ -0,0 +1,227 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use std::convert::TryInto;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for Command {
+  // select * from command order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> Command {
+    Command {
+      command_id: row.get("command_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      scanner_id: row.get("scanner_id"),
+      // means that there's a mismatch between the values of the enum and the value stored in the column
+      command_kind: (row.get::<&str, i64>("command_kind") as u8)
+        .try_into()
+        .unwrap(),
+      duration: row.get("duration"),
+      priority: row.get("priority"),
+      max_attempts: row.get("max_attempts"),
+      firmware_id: row.get("firmware_id"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  scanner_id: String,
+  command_kind: cnc_service_api::request::CommandKind,
+  duration: i64,
+  priority: i64,
+  max_attempts: i64,
+  firmware_id: Option<i64>,
+) -> Result<Command, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let command_id = con
+    .query_one(
+      "INSERT INTO
+       command_t(
+        creation_time,
+        creator_user_id,
+        scanner_id,
+        command_kind,
+        duration,
+        priority,
+        max_attempts,
+        firmware_id
+       )
+       VALUES($1, $2, $3, $4, $5, $6, $7, $8)
+       RETURNING command_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &scanner_id,
+        &(command_kind.clone() as i64),
+        &duration,
+        &priority,
+        &max_attempts,
+        &firmware_id,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return command
+  Ok(Command {
+    command_id,
+    creation_time,
+    creator_user_id,
+    scanner_id,
+    command_kind,
+    duration,
+    priority,
+    max_attempts,
+    firmware_id,
+  })
+}
+
+pub async fn get_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<Option<Command>, tokio_postgres::Error> {
+  let result = con
+    .query_opt("SELECT * FROM command_t WHERE command_id=$1", &[&command_id])
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// the command that should be sent next: not acked, expired or out of attempts,
+// highest priority first, then oldest first. Only commands of the given kinds are considered.
+pub async fn get_next_pending_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  command_kinds: &[cnc_service_api::request::CommandKind],
+) -> Result<Option<Command>, tokio_postgres::Error> {
+  let command_kinds: Vec<i64> = command_kinds.iter().map(|x| x.clone() as i64).collect();
+
+  let result = con
+    .query_opt(
+      "SELECT c.* FROM command_t c
+       LEFT JOIN command_ack_t ca ON ca.command_id = c.command_id
+       WHERE c.scanner_id = $1
+       AND ca.command_ack_id IS NULL
+       AND c.creation_time + c.duration > $2
+       AND (SELECT count(*) FROM command_delivery_t cd WHERE cd.command_id = c.command_id) < c.max_attempts
+       AND c.command_kind = ANY($3)
+       ORDER BY c.priority DESC, c.command_id
+       LIMIT 1
+      ",
+      &[&scanner_id, &current_time_millis(), &command_kinds],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// every command of the given kinds still waiting to be delivered, in the order they will be sent
+pub async fn get_all_pending_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  command_kinds: &[cnc_service_api::request::CommandKind],
+) -> Result<Vec<Command>, tokio_postgres::Error> {
+  let command_kinds: Vec<i64> = command_kinds.iter().map(|x| x.clone() as i64).collect();
+
+  let results = con
+    .query(
+      "SELECT c.* FROM command_t c
+       LEFT JOIN command_ack_t ca ON ca.command_id = c.command_id
+       WHERE c.scanner_id = $1
+       AND ca.command_ack_id IS NULL
+       AND c.creation_time + c.duration > $2
+       AND (SELECT count(*) FROM command_delivery_t cd WHERE cd.command_id = c.command_id) < c.max_attempts
+       AND c.command_kind = ANY($3)
+       ORDER BY c.priority DESC, c.command_id
+      ",
+      &[&scanner_id, &current_time_millis(), &command_kinds],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+// only returns commands sent to scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::CommandViewProps,
+) -> Result<Vec<Command>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT c.* FROM command_t c
+       INNER JOIN scanner_t s ON s.scanner_id = c.scanner_id
+       WHERE 1 = 1
+       AND ($1::bigint[] IS NULL OR c.command_id = ANY($1))
+       AND ($2::bigint   IS NULL OR c.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR c.creation_time <= $3)
+       AND ($4::bigint[] IS NULL OR c.creator_user_id = ANY($4))
+       AND ($5::text[]   IS NULL OR c.scanner_id = ANY($5))
+       AND ($6::bigint   IS NULL OR c.command_kind = $6)
+       AND s.creator_user_id = $7
+       ORDER BY c.command_id
+      ",
+      &[
+        &props.command_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.scanner_id,
+        &props.command_kind.map(|x| x as i64),
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+pub async fn count_acked_by_scanner_id_and_command_kind(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  command_kind: cnc_service_api::request::CommandKind,
+) -> Result<i64, tokio_postgres::Error> {
+  let count: i64 = con
+    .query_one(
+      "SELECT count(DISTINCT c.command_id) FROM command_t c
+       INNER JOIN command_ack_t ca ON ca.command_id = c.command_id
+       WHERE c.scanner_id = $1
+       AND c.command_kind = $2
+      ",
+      &[&scanner_id, &(command_kind as i64)],
+    )
+    .await?
+    .get(0);
+  Ok(count)
+}
+
+// the newest command to flash the firmware onto the scanner
+pub async fn get_latest_by_scanner_id_and_firmware_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  firmware_id: i64,
+) -> Result<Option<Command>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM command_t
+       WHERE scanner_id = $1
+       AND firmware_id = $2
+       ORDER BY command_id DESC
+       LIMIT 1
+      ",
+      &[&scanner_id, &firmware_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
@@ -1,4 +1,10 @@
 use auth_service_api::request::ApiKeyKind;
+use cnc_service_api::request::CommandKind;
+use cnc_service_api::request::FirmwareUpdateKind;
+use cnc_service_api::request::RolloutStatusKind;
+
+use super::device_protocol::SoundKind;
+use super::utils::SeverityKind;
 
 #[derive(Clone, Debug)]
 pub struct User {
@@ -64,3 +70,244 @@ pub struct ApiKey {
   pub api_key_kind: ApiKeyKind,
   pub duration: i64,
 }
+
+#[derive(Clone, Debug)]
+pub struct AuthCard {
+  pub auth_card_id: String,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub school_id: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthCardData {
+  pub auth_card_data_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub auth_card_id: String,
+  pub description: String,
+  pub active: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct FactoryKey {
+  pub uid: String,
+  pub creation_time: i64,
+  pub secret_key: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Scanner {
+  pub scanner_id: String,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub auth_card_id: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerKey {
+  pub scanner_key_id: i64,
+  pub creation_time: i64,
+  pub scanner_id: String,
+  pub scanner_key_hash: String,
+  pub duration: i64,
+  pub last_used_time: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerKeyRevocation {
+  pub scanner_key_revocation_id: i64,
+  pub creation_time: i64,
+  pub scanner_key_id: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerData {
+  pub scanner_data_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub scanner_id: String,
+  pub location_id: Option<i64>,
+  pub description: String,
+  pub active: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerConfig {
+  pub scanner_config_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub scanner_id: String,
+  pub beep_volume: i64,
+  pub led_brightness: i64,
+  pub read_debounce: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerConfigAck {
+  pub scanner_config_ack_id: i64,
+  pub creation_time: i64,
+  pub scanner_config_id: i64,
+}
+
+// not a table, counted from card_read_t
+#[derive(Clone, Debug)]
+pub struct ScannerUsage {
+  pub scanner_id: String,
+  pub creator_user_id: i64,
+  pub lifetime_uses_count: i64,
+  pub month_uses_count: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerCache {
+  pub scanner_cache_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub scanner_id: String,
+  pub average_ping_ms: i64,
+  pub lifetime_uses_count: i64,
+  pub month_uses_count: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CardRead {
+  pub card_read_id: i64,
+  pub creation_time: i64,
+  pub scanner_id: String,
+  pub card_read_epoch: i64,
+  pub device_card_read_id: i64,
+  pub card_payload: Vec<u8>,
+  pub device_time: Option<i64>,
+  pub clock_drift_suspect: bool,
+  pub sound_kind: Option<SoundKind>,
+  pub debounced_card_read_id: Option<i64>,
+  pub offline: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Command {
+  pub command_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub scanner_id: String,
+  pub command_kind: CommandKind,
+  pub duration: i64,
+  pub priority: i64,
+  pub max_attempts: i64,
+  pub firmware_id: Option<i64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandDelivery {
+  pub command_delivery_id: i64,
+  pub creation_time: i64,
+  pub command_id: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandAck {
+  pub command_ack_id: i64,
+  pub creation_time: i64,
+  pub command_id: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerPresence {
+  pub scanner_presence_id: i64,
+  pub creation_time: i64,
+  pub scanner_id: String,
+  pub connected: bool,
+  pub protocol_version: Option<i64>,
+}
+
+// not a table, summed from scanner_presence_t
+#[derive(Clone, Debug)]
+pub struct ScannerUptime {
+  pub scanner_id: String,
+  pub connected_duration: i64,
+}
+
+// firmware_data is left out, since it is only ever read a chunk at a time
+#[derive(Clone, Debug)]
+pub struct Firmware {
+  pub firmware_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub firmware_version: String,
+  pub sha256: String,
+  pub size: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct FirmwareUpdate {
+  pub firmware_update_id: i64,
+  pub creation_time: i64,
+  pub command_id: i64,
+  pub firmware_update_kind: FirmwareUpdateKind,
+  pub transfer_offset: i64,
+  pub previous_firmware_version: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rollout {
+  pub rollout_id: i64,
+  pub creation_time: i64,
+  pub creator_user_id: i64,
+  pub firmware_id: i64,
+  pub location_id: Option<i64>,
+  pub stage_percentages: Vec<i64>,
+  pub failure_threshold_percent: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RolloutData {
+  pub rollout_data_id: i64,
+  pub creation_time: i64,
+  pub rollout_id: i64,
+  pub stage: i64,
+  pub rollout_status_kind: RolloutStatusKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct Telemetry {
+  pub telemetry_id: i64,
+  pub creation_time: i64,
+  pub scanner_id: String,
+  pub resolution: i64,
+  pub sample_count: i64,
+  pub uptime: i64,
+  pub free_heap: i64,
+  pub wifi_rssi: i64,
+  pub temperature: f64,
+  pub reader_errors: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct ScannerLog {
+  pub scanner_log_id: i64,
+  pub creation_time: i64,
+  pub scanner_id: String,
+  pub severity_kind: SeverityKind,
+  pub msg: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct DiagnosticsPart {
+  pub diagnostics_part_id: i64,
+  pub creation_time: i64,
+  pub command_id: i64,
+  pub part_index: i64,
+  pub part_count: i64,
+  pub part_data: Vec<u8>,
+}
+
+// diagnostics_data is left out, since it is only read when downloaded
+#[derive(Clone, Debug)]
+pub struct Diagnostics {
+  pub diagnostics_id: i64,
+  pub creation_time: i64,
+  pub command_id: i64,
+  pub scanner_id: String,
+  pub size: i64,
+}
@@ -0,0 +1,500 @@
+use cnc_service_api::request::CommandKind;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+use super::utils::SeverityKind;
+
+// These are the messages exchanged with the hardware scanners.
+// See the README for the full description of the protocol.
+
+// the newest version of the protocol that the server speaks
+pub static PROTOCOL_VERSION: i64 = 9;
+
+// What a scanner may do in a session. Every capability is introduced in some protocol version,
+// so older scanners never receive, and are never expected to send, newer message kinds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CapabilityKind {
+  CardRead,
+  Command,
+  Cbor,
+  Challenge,
+  KeyRotation,
+  Firmware,
+  Config,
+  Telemetry,
+  Log,
+  Diagnostics,
+  ClockSync,
+  CardReadBatch,
+}
+
+static CAPABILITIES: [CapabilityKind; 12] = [
+  CapabilityKind::CardRead,
+  CapabilityKind::Command,
+  CapabilityKind::Cbor,
+  CapabilityKind::Challenge,
+  CapabilityKind::KeyRotation,
+  CapabilityKind::Firmware,
+  CapabilityKind::Config,
+  CapabilityKind::Telemetry,
+  CapabilityKind::Log,
+  CapabilityKind::Diagnostics,
+  CapabilityKind::ClockSync,
+  CapabilityKind::CardReadBatch,
+];
+
+impl CapabilityKind {
+  // the protocol version that introduced this capability
+  pub fn protocol_version(self) -> i64 {
+    match self {
+      CapabilityKind::CardRead => 1,
+      CapabilityKind::Command => 1,
+      CapabilityKind::Cbor => 2,
+      CapabilityKind::Challenge => 3,
+      CapabilityKind::KeyRotation => 4,
+      CapabilityKind::Firmware => 5,
+      CapabilityKind::Config => 6,
+      CapabilityKind::Telemetry => 7,
+      CapabilityKind::Log => 8,
+      CapabilityKind::Diagnostics => 8,
+      CapabilityKind::ClockSync => 9,
+      CapabilityKind::CardReadBatch => 9,
+    }
+  }
+}
+
+// everything a scanner speaking this protocol version can do
+pub fn capabilities(protocol_version: i64) -> Vec<CapabilityKind> {
+  CAPABILITIES
+    .iter()
+    .copied()
+    .filter(|x| x.protocol_version() <= protocol_version)
+    .collect()
+}
+
+// the capability a scanner needs to run this kind of command
+pub fn command_capability(command_kind: &CommandKind) -> CapabilityKind {
+  match command_kind {
+    CommandKind::PowerCycle => CapabilityKind::Command,
+    CommandKind::FullReset => CapabilityKind::Command,
+    CommandKind::Flash => CapabilityKind::Firmware,
+    CommandKind::Beep => CapabilityKind::Command,
+    CommandKind::UploadDiagnostics => CapabilityKind::Diagnostics,
+  }
+}
+
+// whether a scanner speaking this protocol version can run this kind of command
+pub fn command_allowed(command_kind: &CommandKind, protocol_version: i64) -> bool {
+  command_capability(command_kind).protocol_version() <= protocol_version
+}
+
+// every kind of command a scanner speaking this protocol version can run
+pub fn command_kinds(protocol_version: i64) -> Vec<CommandKind> {
+  vec![
+    CommandKind::PowerCycle,
+    CommandKind::FullReset,
+    CommandKind::Flash,
+    CommandKind::Beep,
+    CommandKind::UploadDiagnostics,
+  ]
+  .into_iter()
+  .filter(|x| command_allowed(x, protocol_version))
+  .collect()
+}
+
+// How messages are written on the websocket.
+// The scanner picks one by the kind of frame it sends STARTUP in.
+// Byte fields are byte strings in CBOR, and arrays of numbers in json, except for firmware.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+  // text frames
+  Json,
+  // binary frames, for scanners that can't afford a json parser
+  Cbor,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisterProps {
+  pub uid: String,
+  pub supervisor_card_id: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RegisterResponse {
+  RegisterSuccess,
+  RegisterFail,
+}
+
+// sent from the scanner to the server over the websocket
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeviceMessage {
+  // scanners that predate versioning send neither version, and speak protocol version 1
+  // api_key is the key from the last STARTUP_SUCCESS or ROTATE_KEY, if the scanner kept it
+  // read_epoch is picked at random whenever the scanner's cardReadId counter restarts
+  #[serde(rename_all = "camelCase")]
+  Startup {
+    uid: String,
+    protocol_version: Option<i64>,
+    firmware_version: Option<String>,
+    api_key: Option<String>,
+    read_epoch: Option<i64>,
+  },
+  // device_time is when the scanner read the card, by the clock it synced at STARTUP
+  #[serde(rename_all = "camelCase")]
+  CardRead {
+    card_read_id: i64,
+    #[serde(with = "serde_bytes")]
+    card_payload: Vec<u8>,
+    device_time: Option<i64>,
+  },
+  // card reads the scanner kept while it was offline
+  #[serde(rename_all = "camelCase")]
+  CardReadBatch {
+    card_reads: Vec<OfflineCardRead>,
+  },
+  #[serde(rename_all = "camelCase")]
+  CommandAck {
+    command_id: i64,
+  },
+  // the HMAC-SHA256 of the challenge nonce, keyed with the scanner's factory secret
+  #[serde(rename_all = "camelCase")]
+  ChallengeResponse {
+    #[serde(with = "serde_bytes")]
+    response: Vec<u8>,
+  },
+  // the scanner has stored the key from ROTATE_KEY, so the old ones may be revoked
+  RotateKeyAck,
+  // asks for part of the firmware of a FLASH command. The scanner resumes by asking from where it left off.
+  #[serde(rename_all = "camelCase")]
+  FirmwareChunkRequest {
+    command_id: i64,
+    offset: i64,
+    length: i64,
+  },
+  // the hex sha256 of the firmware the scanner downloaded
+  #[serde(rename_all = "camelCase")]
+  FirmwareVerify {
+    command_id: i64,
+    sha256: String,
+  },
+  // the scanner has applied this CONFIG
+  #[serde(rename_all = "camelCase")]
+  ConfigAck {
+    config_version: i64,
+  },
+  // sent every so often. reader_errors counts the errors since the last TELEMETRY.
+  #[serde(rename_all = "camelCase")]
+  Telemetry {
+    uptime: i64,
+    free_heap: i64,
+    wifi_rssi: i64,
+    temperature: f64,
+    reader_errors: i64,
+  },
+  #[serde(rename_all = "camelCase")]
+  Log {
+    severity: SeverityKind,
+    msg: String,
+  },
+  // one part of the answer to an UPLOAD_DIAGNOSTICS command
+  #[serde(rename_all = "camelCase")]
+  DiagnosticsPart {
+    command_id: i64,
+    part_index: i64,
+    part_count: i64,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+  },
+}
+
+impl DeviceMessage {
+  // the capability needed to send this message, if any
+  pub fn capability(&self) -> Option<CapabilityKind> {
+    match self {
+      DeviceMessage::Startup { .. } => None,
+      DeviceMessage::CardRead { .. } => Some(CapabilityKind::CardRead),
+      DeviceMessage::CardReadBatch { .. } => Some(CapabilityKind::CardReadBatch),
+      DeviceMessage::CommandAck { .. } => Some(CapabilityKind::Command),
+      DeviceMessage::ChallengeResponse { .. } => Some(CapabilityKind::Challenge),
+      DeviceMessage::RotateKeyAck => Some(CapabilityKind::KeyRotation),
+      DeviceMessage::FirmwareChunkRequest { .. } => Some(CapabilityKind::Firmware),
+      DeviceMessage::FirmwareVerify { .. } => Some(CapabilityKind::Firmware),
+      DeviceMessage::ConfigAck { .. } => Some(CapabilityKind::Config),
+      DeviceMessage::Telemetry { .. } => Some(CapabilityKind::Telemetry),
+      DeviceMessage::Log { .. } => Some(CapabilityKind::Log),
+      DeviceMessage::DiagnosticsPart { .. } => Some(CapabilityKind::Diagnostics),
+    }
+  }
+
+  // whether a scanner speaking this protocol version may send this message
+  pub fn allowed(&self, protocol_version: i64) -> bool {
+    self
+      .capability()
+      .map_or(true, |x| x.protocol_version() <= protocol_version)
+  }
+}
+
+// sent from the server to the scanner over the websocket
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerMessage {
+  // the negotiated version and capabilities are left out for protocol version 1,
+  // and the server time for scanners that can't sync their clock
+  #[serde(rename_all = "camelCase")]
+  StartupSuccess {
+    api_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol_version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capabilities: Option<Vec<CapabilityKind>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_time: Option<i64>,
+  },
+  StartupFail,
+  // sent instead of STARTUP_SUCCESS to scanners that have a factory key
+  #[serde(rename_all = "camelCase")]
+  Challenge { nonce: String },
+  #[serde(rename_all = "camelCase")]
+  CardReadAck { card_read_id: i64, sound: SoundKind },
+  // the scanner may forget these card reads
+  #[serde(rename_all = "camelCase")]
+  CardReadBatchAck { card_read_ids: Vec<i64> },
+  NoStartup,
+  // FLASH commands say which firmware to download
+  #[serde(rename_all = "camelCase")]
+  Command {
+    command_id: i64,
+    command_kind: CommandKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    firmware: Option<FirmwareManifest>,
+  },
+  // replaces the scanner's api key
+  #[serde(rename_all = "camelCase")]
+  RotateKey { api_key: String },
+  #[serde(rename_all = "camelCase")]
+  FirmwareChunk {
+    command_id: i64,
+    offset: i64,
+    #[serde(with = "base64_or_bytes")]
+    data: Vec<u8>,
+  },
+  // the scanner should only flash the firmware if it's valid
+  #[serde(rename_all = "camelCase")]
+  FirmwareVerifyResult { command_id: i64, valid: bool },
+  // sent after STARTUP and whenever the config changes. Versions only ever increase.
+  #[serde(rename_all = "camelCase")]
+  Config {
+    config_version: i64,
+    beep_volume: i64,
+    led_brightness: i64,
+    read_debounce: i64,
+  },
+  // the scanner resends parts that aren't acked
+  #[serde(rename_all = "camelCase")]
+  DiagnosticsPartAck { command_id: i64, part_index: i64 },
+}
+
+// Firmware chunks are big enough that an array of numbers would be several times their size in json,
+// so they are url safe base64 there instead. CBOR has byte strings.
+mod base64_or_bytes {
+  use serde::de::{Deserializer, Error, Visitor};
+  use serde::Serializer;
+  use std::fmt;
+
+  pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+      serializer.serialize_str(&base64_url::encode(data))
+    } else {
+      serializer.serialize_bytes(data)
+    }
+  }
+
+  struct BytesVisitor;
+
+  impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+      formatter.write_str("base64 or a byte string")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Vec<u8>, E> {
+      base64_url::decode(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+      Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+      Ok(v)
+    }
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    deserializer.deserialize_any(BytesVisitor)
+  }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfflineCardRead {
+  pub card_read_id: i64,
+  #[serde(with = "serde_bytes")]
+  pub card_payload: Vec<u8>,
+  pub device_time: i64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareManifest {
+  pub firmware_id: i64,
+  pub firmware_version: String,
+  pub size: i64,
+  pub sha256: String,
+}
+
+// the sound the scanner should play after a card read
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SoundKind {
+  In,
+  Out,
+  Ack,
+  Error,
+  TimedOut,
+}
+
+impl TryFrom<u8> for SoundKind {
+  type Error = u8;
+  fn try_from(val: u8) -> Result<SoundKind, u8> {
+    match val {
+      x if x == SoundKind::In as u8 => Ok(SoundKind::In),
+      x if x == SoundKind::Out as u8 => Ok(SoundKind::Out),
+      x if x == SoundKind::Ack as u8 => Ok(SoundKind::Ack),
+      x if x == SoundKind::Error as u8 => Ok(SoundKind::Error),
+      x if x == SoundKind::TimedOut as u8 => Ok(SoundKind::TimedOut),
+      x => Err(x),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_cbor::Value;
+  use std::collections::BTreeMap;
+
+  #[test]
+  fn version_1_only_reads_cards_and_takes_commands() {
+    assert_eq!(
+      capabilities(1),
+      vec![CapabilityKind::CardRead, CapabilityKind::Command]
+    );
+  }
+
+  #[test]
+  fn newest_version_has_every_capability() {
+    assert_eq!(capabilities(PROTOCOL_VERSION), CAPABILITIES.to_vec());
+  }
+
+  #[test]
+  fn capabilities_never_exceed_the_version() {
+    for protocol_version in 1..=PROTOCOL_VERSION {
+      assert!(capabilities(protocol_version)
+        .iter()
+        .all(|x| x.protocol_version() <= protocol_version));
+    }
+  }
+
+  #[test]
+  fn commands_are_gated_by_version() {
+    assert!(command_allowed(&CommandKind::Beep, 1));
+    assert!(!command_allowed(&CommandKind::Flash, 4));
+    assert!(command_allowed(&CommandKind::Flash, 5));
+    assert!(!command_allowed(&CommandKind::UploadDiagnostics, 7));
+    assert!(command_allowed(&CommandKind::UploadDiagnostics, 8));
+    assert_eq!(command_kinds(4).len(), 3);
+    assert_eq!(command_kinds(PROTOCOL_VERSION).len(), 5);
+  }
+
+  #[test]
+  fn messages_are_gated_by_version() {
+    let telemetry = DeviceMessage::Telemetry {
+      uptime: 3600,
+      free_heap: 40960,
+      wifi_rssi: -67,
+      temperature: 41.5,
+      reader_errors: 0,
+    };
+    assert!(!telemetry.allowed(6));
+    assert!(telemetry.allowed(7));
+    assert!(DeviceMessage::CommandAck { command_id: 1 }.allowed(1));
+    assert!(!DeviceMessage::RotateKeyAck.allowed(3));
+  }
+
+  #[test]
+  fn startup_is_always_allowed() {
+    let startup = DeviceMessage::Startup {
+      uid: "uid".to_owned(),
+      protocol_version: None,
+      firmware_version: None,
+      api_key: None,
+      read_epoch: None,
+    };
+    assert!(startup.allowed(1));
+  }
+
+  #[test]
+  fn card_payload_decodes_from_a_cbor_byte_string() {
+    let mut map = BTreeMap::new();
+    map.insert(Value::Text("kind".to_owned()), Value::Text("CARD_READ".to_owned()));
+    map.insert(Value::Text("cardReadId".to_owned()), Value::Integer(1));
+    map.insert(Value::Text("cardPayload".to_owned()), Value::Bytes(vec![12, 12, 123]));
+    let cbor = serde_cbor::to_vec(&Value::Map(map)).unwrap();
+
+    match serde_cbor::from_slice::<DeviceMessage>(&cbor).unwrap() {
+      DeviceMessage::CardRead { card_payload, .. } => assert_eq!(card_payload, vec![12, 12, 123]),
+      x => panic!("decoded {:?}", x),
+    }
+  }
+
+  #[test]
+  fn card_payload_decodes_from_a_json_array() {
+    let json = r#"{ "kind": "CARD_READ", "cardReadId": 1, "cardPayload": [12, 12, 123] }"#;
+    match serde_json::from_str::<DeviceMessage>(json).unwrap() {
+      DeviceMessage::CardRead { card_payload, .. } => assert_eq!(card_payload, vec![12, 12, 123]),
+      x => panic!("decoded {:?}", x),
+    }
+  }
+
+  fn firmware_chunk() -> ServerMessage {
+    ServerMessage::FirmwareChunk {
+      command_id: 1,
+      offset: 0,
+      data: vec![12, 12, 123],
+    }
+  }
+
+  #[test]
+  fn firmware_chunk_is_base64_in_json() {
+    let json = serde_json::to_value(&firmware_chunk()).unwrap();
+    assert_eq!(json["data"], serde_json::Value::String(base64_url::encode(&[12, 12, 123])));
+  }
+
+  #[test]
+  fn firmware_chunk_is_a_byte_string_in_cbor() {
+    let cbor = serde_cbor::to_vec(&firmware_chunk()).unwrap();
+    match serde_cbor::from_slice::<Value>(&cbor).unwrap() {
+      Value::Map(map) => assert_eq!(
+        map.get(&Value::Text("data".to_owned())),
+        Some(&Value::Bytes(vec![12, 12, 123]))
+      ),
+      x => panic!("decoded {:?}", x),
+    }
+  }
+}
@@ -0,0 +1,111 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for DiagnosticsPart {
+  // select * from diagnostics_part order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> DiagnosticsPart {
+    DiagnosticsPart {
+      diagnostics_part_id: row.get("diagnostics_part_id"),
+      creation_time: row.get("creation_time"),
+      command_id: row.get("command_id"),
+      part_index: row.get("part_index"),
+      part_count: row.get("part_count"),
+      part_data: row.get("part_data"),
+    }
+  }
+}
+
+// parts that were already received are ignored, since scanners resend parts they didn't get an ack for
+pub async fn add(
+  con: &mut impl GenericClient,
+  command_id: i64,
+  part_index: i64,
+  part_count: i64,
+  part_data: Vec<u8>,
+) -> Result<(), tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  con
+    .execute(
+      "INSERT INTO
+       diagnostics_part_t(
+        creation_time,
+        command_id,
+        part_index,
+        part_count,
+        part_data
+       )
+       VALUES($1, $2, $3, $4, $5)
+       ON CONFLICT (command_id, part_index) DO NOTHING
+      ",
+      &[
+        &creation_time,
+        &command_id,
+        &part_index,
+        &part_count,
+        &part_data,
+      ],
+    )
+    .await?;
+
+  Ok(())
+}
+
+pub async fn count_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<i64, tokio_postgres::Error> {
+  let count: i64 = con
+    .query_one(
+      "SELECT count(*) FROM diagnostics_part_t WHERE command_id=$1",
+      &[&command_id],
+    )
+    .await?
+    .get(0);
+  Ok(count)
+}
+
+// how many parts the stored parts say there are, None if none are stored
+pub async fn get_part_count_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<Option<i64>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT part_count FROM diagnostics_part_t WHERE command_id=$1 LIMIT 1",
+      &[&command_id],
+    )
+    .await?
+    .map(|row| row.get(0));
+
+  Ok(result)
+}
+
+pub async fn get_all_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<Vec<DiagnosticsPart>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT * FROM diagnostics_part_t WHERE command_id=$1 ORDER BY part_index",
+      &[&command_id],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+pub async fn delete_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<u64, tokio_postgres::Error> {
+  con
+    .execute(
+      "DELETE FROM diagnostics_part_t WHERE command_id=$1",
+      &[&command_id],
+    )
+    .await
+}
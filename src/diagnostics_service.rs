@@ -0,0 +1,141 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+// everything but diagnostics_data, which can be large
+static DIAGNOSTICS_COLUMNS: &str =
+  "d.diagnostics_id, d.creation_time, d.command_id, d.scanner_id, length(d.diagnostics_data) AS size";
+
+impl From<tokio_postgres::row::Row> for Diagnostics {
+  // select DIAGNOSTICS_COLUMNS only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> Diagnostics {
+    Diagnostics {
+      diagnostics_id: row.get("diagnostics_id"),
+      creation_time: row.get("creation_time"),
+      command_id: row.get("command_id"),
+      scanner_id: row.get("scanner_id"),
+      size: row.get::<&str, i32>("size") as i64,
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  command_id: i64,
+  scanner_id: String,
+  diagnostics_data: Vec<u8>,
+) -> Result<Diagnostics, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+  let size = diagnostics_data.len() as i64;
+
+  let diagnostics_id = con
+    .query_one(
+      "INSERT INTO
+       diagnostics_t(
+        creation_time,
+        command_id,
+        scanner_id,
+        diagnostics_data
+       )
+       VALUES($1, $2, $3, $4)
+       RETURNING diagnostics_id
+      ",
+      &[&creation_time, &command_id, &scanner_id, &diagnostics_data],
+    )
+    .await?
+    .get(0);
+
+  // return diagnostics
+  Ok(Diagnostics {
+    diagnostics_id,
+    creation_time,
+    command_id,
+    scanner_id,
+    size,
+  })
+}
+
+pub async fn get_by_diagnostics_id(
+  con: &mut impl GenericClient,
+  diagnostics_id: i64,
+) -> Result<Option<Diagnostics>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      &*format!("SELECT {} FROM diagnostics_t d WHERE d.diagnostics_id=$1", DIAGNOSTICS_COLUMNS),
+      &[&diagnostics_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+pub async fn exists_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<bool, tokio_postgres::Error> {
+  let count: i64 = con
+    .query_one(
+      "SELECT count(*) FROM diagnostics_t WHERE command_id=$1",
+      &[&command_id],
+    )
+    .await?
+    .get(0);
+  Ok(count != 0)
+}
+
+pub async fn get_data_by_diagnostics_id(
+  con: &mut impl GenericClient,
+  diagnostics_id: i64,
+) -> Result<Option<Vec<u8>>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT diagnostics_data FROM diagnostics_t WHERE diagnostics_id=$1",
+      &[&diagnostics_id],
+    )
+    .await?
+    .map(|row| row.get(0));
+
+  Ok(result)
+}
+
+// only returns diagnostics from scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::DiagnosticsViewProps,
+) -> Result<Vec<Diagnostics>, tokio_postgres::Error> {
+  let sql = [
+    &*format!("SELECT {} FROM diagnostics_t d", DIAGNOSTICS_COLUMNS),
+    " INNER JOIN scanner_t s ON s.scanner_id = d.scanner_id",
+    " WHERE 1 = 1",
+    " AND ($1::bigint[] IS NULL OR d.diagnostics_id = ANY($1))",
+    " AND ($2::bigint   IS NULL OR d.creation_time >= $2)",
+    " AND ($3::bigint   IS NULL OR d.creation_time <= $3)",
+    " AND ($4::bigint[] IS NULL OR d.command_id = ANY($4))",
+    " AND ($5::text[]   IS NULL OR d.scanner_id = ANY($5))",
+    " AND s.creator_user_id = $6",
+    " ORDER BY d.diagnostics_id",
+  ]
+  .join("\n");
+
+  let stmnt = con.prepare(&sql).await?;
+
+  let results = con
+    .query(
+      &stmnt,
+      &[
+        &props.diagnostics_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.command_id,
+        &props.scanner_id,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
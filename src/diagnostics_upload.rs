@@ -0,0 +1,133 @@
+use std::error::Error;
+
+use super::command_service;
+use super::device_protocol::ServerMessage;
+use super::diagnostics_part_service;
+use super::diagnostics_service;
+use super::utils;
+use super::Db;
+
+use cnc_service_api::request::CommandKind;
+
+// Scanners answer UPLOAD_DIAGNOSTICS with their diagnostics split into parts, acking each one.
+// Parts may arrive in any order and more than once, across any number of sessions.
+// Once every part is in, they are joined together and can be downloaded.
+// A part that disagrees with the stored ones on the part count means the scanner started over, say after
+// restarting, so the stored parts are dropped and the upload starts over with it.
+
+// the most parts a scanner may split its diagnostics into
+static MAX_PART_COUNT: i64 = 1024;
+
+// the biggest a part may be
+static MAX_PART_SIZE: usize = 64 * 1024;
+
+fn report_postgres_err(e: tokio_postgres::Error) {
+  utils::log(utils::Event {
+    msg: e.to_string(),
+    source: e.source().map(|e| e.to_string()),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+fn report_upload_err(msg: String, scanner_id: &str) {
+  utils::log(utils::Event {
+    msg,
+    source: Some(format!("diagnostics upload: {}", scanner_id)),
+    severity: utils::SeverityKind::Warning,
+  });
+}
+
+pub async fn part(
+  db: &Db,
+  scanner_id: &str,
+  command_id: i64,
+  part_index: i64,
+  part_count: i64,
+  data: Vec<u8>,
+) -> Result<ServerMessage, ()> {
+  if !(1..=MAX_PART_COUNT).contains(&part_count)
+    || !(0..part_count).contains(&part_index)
+    || data.len() > MAX_PART_SIZE
+  {
+    report_upload_err(
+      format!("part {} of {} for command {} is malformed", part_index, part_count, command_id),
+      scanner_id,
+    );
+    return Err(());
+  }
+
+  let con = &mut *db.lock().await;
+
+  // scanners may only answer their own commands
+  command_service::get_by_command_id(con, command_id)
+    .await
+    .map_err(report_postgres_err)?
+    .filter(|x| x.scanner_id == scanner_id)
+    .filter(|x| matches!(x.command_kind, CommandKind::UploadDiagnostics))
+    .ok_or_else(|| {
+      report_upload_err(
+        format!("no such diagnostics command {}", command_id),
+        scanner_id,
+      )
+    })?;
+
+  let ack = ServerMessage::DiagnosticsPartAck {
+    command_id,
+    part_index,
+  };
+
+  // the scanner is resending a part whose ack got lost
+  if diagnostics_service::exists_by_command_id(con, command_id)
+    .await
+    .map_err(report_postgres_err)?
+  {
+    return Ok(ack);
+  }
+
+  let mut sp = con.transaction().await.map_err(report_postgres_err)?;
+
+  let stored_part_count = diagnostics_part_service::get_part_count_by_command_id(&mut sp, command_id)
+    .await
+    .map_err(report_postgres_err)?;
+
+  if let Some(stored_part_count) = stored_part_count.filter(|x| *x != part_count) {
+    report_upload_err(
+      format!(
+        "command {} started over with {} parts instead of {}",
+        command_id, part_count, stored_part_count
+      ),
+      scanner_id,
+    );
+    diagnostics_part_service::delete_by_command_id(&mut sp, command_id)
+      .await
+      .map_err(report_postgres_err)?;
+  }
+
+  diagnostics_part_service::add(&mut sp, command_id, part_index, part_count, data)
+    .await
+    .map_err(report_postgres_err)?;
+
+  let received_count = diagnostics_part_service::count_by_command_id(&mut sp, command_id)
+    .await
+    .map_err(report_postgres_err)?;
+
+  if received_count == part_count {
+    let parts = diagnostics_part_service::get_all_by_command_id(&mut sp, command_id)
+      .await
+      .map_err(report_postgres_err)?;
+
+    let diagnostics_data = parts.into_iter().flat_map(|x| x.part_data).collect();
+
+    diagnostics_service::add(&mut sp, command_id, scanner_id.to_owned(), diagnostics_data)
+      .await
+      .map_err(report_postgres_err)?;
+
+    diagnostics_part_service::delete_by_command_id(&mut sp, command_id)
+      .await
+      .map_err(report_postgres_err)?;
+  }
+
+  sp.commit().await.map_err(report_postgres_err)?;
+
+  Ok(ack)
+}
@@ -0,0 +1,55 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for FactoryKey {
+  // select * from factory_key order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> FactoryKey {
+    FactoryKey {
+      uid: row.get("uid"),
+      creation_time: row.get("creation_time"),
+      secret_key: row.get("secret_key"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  uid: String,
+  secret_key: String,
+) -> Result<FactoryKey, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  con
+    .execute(
+      "INSERT INTO
+       factory_key_t(
+        uid,
+        creation_time,
+        secret_key
+       )
+       VALUES($1, $2, $3)
+      ",
+      &[&uid, &creation_time, &secret_key],
+    )
+    .await?;
+
+  // return factory key
+  Ok(FactoryKey {
+    uid,
+    creation_time,
+    secret_key,
+  })
+}
+
+pub async fn get_by_uid(
+  con: &mut impl GenericClient,
+  uid: &str,
+) -> Result<Option<FactoryKey>, tokio_postgres::Error> {
+  let result = con
+    .query_opt("SELECT * FROM factory_key_t WHERE uid=$1", &[&uid])
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
@@ -0,0 +1,45 @@
+use super::device_protocol::SoundKind;
+use serde::{Deserialize, Serialize};
+
+// These are the messages exchanged with the microservices listening on /feed.
+// See the README for the full description of the protocol.
+
+// sent from the server to every listening microservice
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FeedEvent {
+  #[serde(rename_all = "camelCase")]
+  CardRead {
+    device_id: String,
+    card_read_id: i64,
+    card_payload: Vec<u8>,
+  },
+  // read while the scanner was offline, so it has long since played its sound and needs no answer
+  #[serde(rename_all = "camelCase")]
+  OfflineCardRead {
+    device_id: String,
+    card_read_id: i64,
+    card_payload: Vec<u8>,
+    device_time: i64,
+  },
+  // creator_user_id is the account the scanner was registered to
+  #[serde(rename_all = "camelCase")]
+  Initialize {
+    device_id: String,
+    uid: String,
+    creator_user_id: i64,
+    supervisor_card_id: String,
+  },
+}
+
+// sent from a listening microservice to the server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FeedMessage {
+  #[serde(rename_all = "camelCase")]
+  CardReadAck {
+    device_id: String,
+    card_read_id: i64,
+    sound: SoundKind,
+  },
+}
@@ -0,0 +1,232 @@
+use futures::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use warp::ws::{Message, WebSocket};
+
+use super::db_types::Scanner;
+use super::device_protocol::SoundKind;
+use super::feed_protocol::{FeedEvent, FeedMessage};
+use super::utils;
+use super::Feed;
+
+static NEXT_LISTENER_ID: AtomicU64 = AtomicU64::new(0);
+
+pub struct FeedState {
+  // every connected microservice
+  listeners: HashMap<u64, mpsc::UnboundedSender<FeedEvent>>,
+  // card reads waiting on a listener's answer, keyed by (deviceId, cardReadId)
+  pending: HashMap<(String, i64), oneshot::Sender<SoundKind>>,
+  // repeats of a card read that is still being decided, keyed by the card_read_id it repeats
+  repeats: HashMap<i64, Vec<oneshot::Sender<SoundKind>>>,
+  // how long a listener has to decide what sound a card read should make
+  card_read_timeout: Duration,
+}
+
+impl FeedState {
+  pub fn new(card_read_timeout: Duration) -> FeedState {
+    FeedState {
+      listeners: HashMap::new(),
+      pending: HashMap::new(),
+      repeats: HashMap::new(),
+      card_read_timeout,
+    }
+  }
+
+  fn broadcast(&self, event: FeedEvent) {
+    for listener in self.listeners.values() {
+      // if this fails, the listener is disconnecting and will be removed shortly
+      let _ = listener.send(event.clone());
+    }
+  }
+}
+
+fn report_protocol_err(msg: String) {
+  utils::log(utils::Event {
+    msg,
+    source: Some("feed socket".to_owned()),
+    severity: utils::SeverityKind::Warning,
+  });
+}
+
+// the scanner plays TIMED_OUT, so someone should hear about why
+fn report_unanswered(msg: String, device_id: &str) {
+  utils::log(utils::Event {
+    msg,
+    source: Some(format!("feed socket: device {}", device_id)),
+    severity: utils::SeverityKind::Warning,
+  });
+}
+
+// this function runs for as long as the microservice stays connected
+pub async fn handle(ws: WebSocket, feed: Feed) {
+  let (mut ws_tx, mut ws_rx) = ws.split();
+
+  let (sender, mut receiver) = mpsc::unbounded_channel::<FeedEvent>();
+
+  // forward every event out over the websocket
+  tokio::spawn(async move {
+    while let Some(event) = receiver.recv().await {
+      let text = serde_json::to_string(&event).unwrap();
+      if let Err(e) = ws_tx.send(Message::text(text)).await {
+        report_protocol_err(e.to_string());
+        break;
+      }
+    }
+  });
+
+  let listener_id = NEXT_LISTENER_ID.fetch_add(1, Ordering::Relaxed);
+  feed.lock().await.listeners.insert(listener_id, sender);
+
+  while let Some(result) = ws_rx.next().await {
+    let msg = match result {
+      Ok(msg) => msg,
+      Err(e) => {
+        report_protocol_err(e.to_string());
+        break;
+      }
+    };
+
+    if msg.is_close() {
+      break;
+    }
+
+    let text = match msg.to_str() {
+      Ok(text) => text,
+      Err(()) => continue,
+    };
+
+    match serde_json::from_str::<FeedMessage>(text) {
+      Ok(FeedMessage::CardReadAck {
+        device_id,
+        card_read_id,
+        sound,
+      }) => {
+        // the first listener to answer decides, later answers are dropped
+        if let Some(pending) = feed.lock().await.pending.remove(&(device_id, card_read_id)) {
+          let _ = pending.send(sound);
+        }
+      }
+      Err(e) => report_protocol_err(e.to_string()),
+    }
+  }
+
+  feed.lock().await.listeners.remove(&listener_id);
+}
+
+// sends the card read to every listener and waits for the first one to pick a sound
+pub async fn forward_card_read(
+  feed: &Feed,
+  device_id: String,
+  card_read_id: i64,
+  card_payload: Vec<u8>,
+) -> SoundKind {
+  let key = (device_id.clone(), card_read_id);
+
+  let (sender, receiver) = oneshot::channel();
+
+  let card_read_timeout = {
+    let mut feed = feed.lock().await;
+
+    // nobody could ever answer
+    if feed.listeners.is_empty() {
+      report_unanswered(
+        format!("card read {} with no listener connected", card_read_id),
+        &device_id,
+      );
+      return SoundKind::TimedOut;
+    }
+
+    feed.pending.insert(key.clone(), sender);
+    feed.broadcast(FeedEvent::CardRead {
+      device_id: device_id.clone(),
+      card_read_id,
+      card_payload,
+    });
+
+    feed.card_read_timeout
+  };
+
+  match tokio::time::timeout(card_read_timeout, receiver).await {
+    Ok(Ok(sound)) => sound,
+    // never happens, each read is forwarded by one caller under its own card_read_id
+    Ok(Err(_)) => SoundKind::TimedOut,
+    Err(_) => {
+      feed.lock().await.pending.remove(&key);
+      report_unanswered(
+        format!("no listener answered card read {} in time", card_read_id),
+        &device_id,
+      );
+      SoundKind::TimedOut
+    }
+  }
+}
+
+// lets every listener know about a card read that happened while the scanner was offline
+pub async fn forward_offline_card_read(
+  feed: &Feed,
+  device_id: String,
+  card_read_id: i64,
+  card_payload: Vec<u8>,
+  device_time: i64,
+) {
+  feed.lock().await.broadcast(FeedEvent::OfflineCardRead {
+    device_id,
+    card_read_id,
+    card_payload,
+    device_time,
+  });
+}
+
+// lets every listener know that a new scanner has been registered
+pub async fn initialize(feed: &Feed, scanner: Scanner) {
+  feed.lock().await.broadcast(FeedEvent::Initialize {
+    // scanners are keyed by their uid, so the two are the same
+    device_id: scanner.scanner_id.clone(),
+    uid: scanner.scanner_id,
+    creator_user_id: scanner.creator_user_id,
+    supervisor_card_id: scanner.auth_card_id,
+  });
+}
+
+// Marks a card read as being decided by its forwarder, so that repeats of it can wait for its
+// sound. The caller must hold the db while storing the read and calling this.
+pub async fn start_deciding(feed: &Feed, card_read_id: i64) {
+  feed.lock().await.repeats.entry(card_read_id).or_insert_with(Vec::new);
+}
+
+// Registers a repeat of a card read that hasn't been decided yet. The caller must hold the db
+// while checking that the read is undecided and calling this, so the decision can't slip in between.
+// Returns None if nobody is deciding the read any more, e.g. the server restarted while forwarding it.
+pub async fn expect_sound(feed: &Feed, card_read_id: i64) -> Option<oneshot::Receiver<SoundKind>> {
+  let (sender, receiver) = oneshot::channel();
+  feed.lock().await.repeats.get_mut(&card_read_id)?.push(sender);
+  Some(receiver)
+}
+
+// waits for the repeated card read to be decided, for no longer than the listeners get
+pub async fn wait_for_sound(
+  feed: &Feed,
+  card_read_id: i64,
+  receiver: oneshot::Receiver<SoundKind>,
+) -> SoundKind {
+  let card_read_timeout = feed.lock().await.card_read_timeout;
+  match tokio::time::timeout(card_read_timeout, receiver).await {
+    Ok(Ok(sound)) => sound,
+    _ => {
+      // our receiver is gone, so drop its sender rather than keep it until the read is decided
+      if let Some(repeats) = feed.lock().await.repeats.get_mut(&card_read_id) {
+        repeats.retain(|x| !x.is_closed());
+      }
+      SoundKind::TimedOut
+    }
+  }
+}
+
+// gives everyone repeating the card read the sound it was decided to make
+pub async fn resolve_sound(feed: &Feed, card_read_id: i64, sound: SoundKind) {
+  for repeat in feed.lock().await.repeats.remove(&card_read_id).unwrap_or_default() {
+    let _ = repeat.send(sound.clone());
+  }
+}
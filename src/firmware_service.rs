@@ -0,0 +1,160 @@
+use super::db_types::*;
+use super::utils;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+// everything but firmware_data, which can be large
+static FIRMWARE_COLUMNS: &str = "f.firmware_id, f.creation_time, f.creator_user_id, f.firmware_version, f.sha256, f.size";
+
+impl From<tokio_postgres::row::Row> for Firmware {
+  // select FIRMWARE_COLUMNS only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> Firmware {
+    Firmware {
+      firmware_id: row.get("firmware_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      firmware_version: row.get("firmware_version"),
+      sha256: row.get("sha256"),
+      size: row.get("size"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  firmware_version: String,
+  firmware_data: Vec<u8>,
+) -> Result<Firmware, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+  let sha256 = utils::sha256_hex(&firmware_data);
+  let size = firmware_data.len() as i64;
+
+  let firmware_id = con
+    .query_one(
+      "INSERT INTO
+       firmware_t(
+        creation_time,
+        creator_user_id,
+        firmware_version,
+        sha256,
+        size,
+        firmware_data
+       )
+       VALUES($1, $2, $3, $4, $5, $6)
+       RETURNING firmware_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &firmware_version,
+        &sha256,
+        &size,
+        &firmware_data,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return firmware
+  Ok(Firmware {
+    firmware_id,
+    creation_time,
+    creator_user_id,
+    firmware_version,
+    sha256,
+    size,
+  })
+}
+
+pub async fn get_by_firmware_id(
+  con: &mut impl GenericClient,
+  firmware_id: i64,
+) -> Result<Option<Firmware>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      &*format!("SELECT {} FROM firmware_t f WHERE f.firmware_id=$1", FIRMWARE_COLUMNS),
+      &[&firmware_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// versions are only unique among one user's firmware
+pub async fn get_by_creator_user_id_and_firmware_version(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  firmware_version: &str,
+) -> Result<Option<Firmware>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      &*format!(
+        "SELECT {} FROM firmware_t f WHERE f.creator_user_id=$1 AND f.firmware_version=$2",
+        FIRMWARE_COLUMNS
+      ),
+      &[&creator_user_id, &firmware_version],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// offset is zero based. Chunks past the end of the image are empty.
+pub async fn get_chunk(
+  con: &mut impl GenericClient,
+  firmware_id: i64,
+  offset: i64,
+  length: i64,
+) -> Result<Option<Vec<u8>>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT substring(firmware_data FROM $2 FOR $3) FROM firmware_t WHERE firmware_id=$1",
+      &[&firmware_id, &(offset + 1), &length],
+    )
+    .await?
+    .map(|row| row.get(0));
+
+  Ok(result)
+}
+
+// only returns firmware uploaded by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::FirmwareViewProps,
+) -> Result<Vec<Firmware>, tokio_postgres::Error> {
+  let sql = [
+    &*format!("SELECT {} FROM firmware_t f WHERE 1 = 1", FIRMWARE_COLUMNS),
+    " AND f.creator_user_id = $6",
+    " AND ($1::bigint[] IS NULL OR f.firmware_id = ANY($1))",
+    " AND ($2::bigint   IS NULL OR f.creation_time >= $2)",
+    " AND ($3::bigint   IS NULL OR f.creation_time <= $3)",
+    " AND ($4::bigint[] IS NULL OR f.creator_user_id = ANY($4))",
+    " AND ($5::text[]   IS NULL OR f.firmware_version = ANY($5))",
+    " ORDER BY f.firmware_id",
+  ]
+  .join("\n");
+
+  let stmnt = con.prepare(&sql).await?;
+
+  let results = con
+    .query(
+      &stmnt,
+      &[
+        &props.firmware_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.firmware_version,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
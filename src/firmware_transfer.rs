@@ -0,0 +1,222 @@
+use std::collections::HashSet;
+use std::error::Error;
+
+use super::command_service;
+use super::db_types::{Command, Firmware};
+use super::device_protocol::ServerMessage;
+use super::firmware_service;
+use super::firmware_update_service;
+use super::utils;
+use super::Db;
+
+use cnc_service_api::request::FirmwareUpdateKind;
+
+// Scanners download the firmware of a FLASH command one chunk at a time, verify it, flash it and restart.
+// The scanner drives the transfer, so it can resume from wherever it was after losing its connection.
+// The update is only finished once the scanner starts up running something other than what it verified on.
+
+// the most a scanner may ask for at once
+static FIRMWARE_CHUNK_SIZE: i64 = 16 * 1024;
+
+fn report_postgres_err(e: tokio_postgres::Error) {
+  utils::log(utils::Event {
+    msg: e.to_string(),
+    source: e.source().map(|e| e.to_string()),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+fn report_transfer_err(msg: String, scanner_id: &str) {
+  utils::log(utils::Event {
+    msg,
+    source: Some(format!("firmware transfer: {}", scanner_id)),
+    severity: utils::SeverityKind::Warning,
+  });
+}
+
+// the scanner may only download firmware of its own FLASH commands
+async fn get_flash_command(
+  con: &mut tokio_postgres::Client,
+  scanner_id: &str,
+  command_id: i64,
+) -> Result<(Command, Firmware), ()> {
+  let command = command_service::get_by_command_id(con, command_id)
+    .await
+    .map_err(report_postgres_err)?
+    .filter(|x| x.scanner_id == scanner_id)
+    .ok_or_else(|| report_transfer_err(format!("no such command {}", command_id), scanner_id))?;
+
+  let firmware_id = command.firmware_id.ok_or_else(|| {
+    report_transfer_err(format!("command {} has no firmware", command_id), scanner_id)
+  })?;
+
+  let firmware = firmware_service::get_by_firmware_id(con, firmware_id)
+    .await
+    .map_err(report_postgres_err)?
+    .ok_or_else(|| report_transfer_err(format!("no such firmware {}", firmware_id), scanner_id))?;
+
+  Ok((command, firmware))
+}
+
+// transfers holds the commands whose transfer was already recorded in this session
+pub async fn chunk(
+  db: &Db,
+  scanner_id: &str,
+  transfers: &mut HashSet<i64>,
+  command_id: i64,
+  offset: i64,
+  length: i64,
+) -> Option<ServerMessage> {
+  let con = &mut *db.lock().await;
+
+  let (command, firmware) = get_flash_command(con, scanner_id, command_id).await.ok()?;
+
+  if offset < 0 || offset > firmware.size || length < 0 {
+    report_transfer_err(
+      format!("chunk at {} of {} out of bounds", offset, firmware.size),
+      scanner_id,
+    );
+    return None;
+  }
+
+  // a verified or flashed update is done downloading, and mustn't go back to transferring
+  let finished = firmware_update_service::get_by_command_id(con, command.command_id)
+    .await
+    .map_err(report_postgres_err)
+    .ok()?
+    .map_or(false, |x| {
+      matches!(
+        x.firmware_update_kind,
+        FirmwareUpdateKind::Verified | FirmwareUpdateKind::Succeeded
+      )
+    });
+
+  if finished {
+    report_transfer_err(format!("chunk for finished command {}", command_id), scanner_id);
+    return None;
+  }
+
+  // only the first chunk of a session is recorded, so resumed transfers show where they resumed
+  if transfers.insert(command.command_id) {
+    if let Err(e) = firmware_update_service::add(
+      con,
+      command.command_id,
+      FirmwareUpdateKind::Transferring,
+      offset,
+      None,
+    )
+    .await
+    {
+      report_postgres_err(e);
+    }
+  }
+
+  let data = firmware_service::get_chunk(
+    con,
+    firmware.firmware_id,
+    offset,
+    length.min(FIRMWARE_CHUNK_SIZE),
+  )
+  .await
+  .map_err(report_postgres_err)
+  .ok()??;
+
+  Some(ServerMessage::FirmwareChunk {
+    command_id,
+    offset,
+    data,
+  })
+}
+
+// firmware_version is what the scanner reported at STARTUP, so it is running it while it verifies
+pub async fn verify(
+  db: &Db,
+  scanner_id: &str,
+  firmware_version: Option<String>,
+  command_id: i64,
+  sha256: String,
+) -> Option<ServerMessage> {
+  let con = &mut *db.lock().await;
+
+  let (command, firmware) = get_flash_command(con, scanner_id, command_id).await.ok()?;
+
+  let valid = sha256.eq_ignore_ascii_case(&firmware.sha256);
+
+  if !valid {
+    report_transfer_err(
+      format!("firmware {} arrived corrupted", firmware.firmware_id),
+      scanner_id,
+    );
+  }
+
+  if let Err(e) = firmware_update_service::add(
+    con,
+    command.command_id,
+    if valid {
+      FirmwareUpdateKind::Verified
+    } else {
+      FirmwareUpdateKind::Corrupted
+    },
+    firmware.size,
+    firmware_version,
+  )
+  .await
+  {
+    report_postgres_err(e);
+  }
+
+  Some(ServerMessage::FirmwareVerifyResult { command_id, valid })
+}
+
+// A scanner that verified some firmware restarts into it. If it restarted into anything else, the flash failed.
+// Starting up with the version it verified on means it never got to flash, say because it lost its connection
+// before restarting, so the update is left to finish at a later startup.
+pub async fn startup(
+  con: &mut tokio_postgres::Client,
+  scanner_id: &str,
+  firmware_version: Option<&str>,
+) -> Result<(), tokio_postgres::Error> {
+  // without a version there's no telling what the scanner restarted into
+  let firmware_version = match firmware_version {
+    Some(firmware_version) => firmware_version,
+    None => return Ok(()),
+  };
+
+  let verified_updates =
+    firmware_update_service::get_recent_by_scanner_id(con, scanner_id, FirmwareUpdateKind::Verified)
+      .await?;
+
+  for firmware_update in verified_updates {
+    let firmware = match command_service::get_by_command_id(con, firmware_update.command_id)
+      .await?
+      .and_then(|x| x.firmware_id)
+    {
+      Some(firmware_id) => firmware_service::get_by_firmware_id(con, firmware_id).await?,
+      None => None,
+    };
+
+    let not_restarted =
+      Some(firmware_version) == firmware_update.previous_firmware_version.as_deref();
+
+    let succeeded = firmware.map_or(false, |x| x.firmware_version == firmware_version);
+
+    if not_restarted && !succeeded {
+      continue;
+    }
+
+    firmware_update_service::add(
+      con,
+      firmware_update.command_id,
+      if succeeded {
+        FirmwareUpdateKind::Succeeded
+      } else {
+        FirmwareUpdateKind::Failed
+      },
+      firmware_update.transfer_offset,
+      None,
+    )
+    .await?;
+  }
+
+  Ok(())
+}
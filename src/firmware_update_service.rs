@@ -0,0 +1,154 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use cnc_service_api::request::FirmwareUpdateKind;
+use std::convert::TryInto;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for FirmwareUpdate {
+  // select * from firmware_update order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> FirmwareUpdate {
+    FirmwareUpdate {
+      firmware_update_id: row.get("firmware_update_id"),
+      creation_time: row.get("creation_time"),
+      command_id: row.get("command_id"),
+      // means that there's a mismatch between the values of the enum and the value stored in the column
+      firmware_update_kind: (row.get::<&str, i64>("firmware_update_kind") as u8)
+        .try_into()
+        .unwrap(),
+      transfer_offset: row.get("transfer_offset"),
+      previous_firmware_version: row.get("previous_firmware_version"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  command_id: i64,
+  firmware_update_kind: FirmwareUpdateKind,
+  transfer_offset: i64,
+  previous_firmware_version: Option<String>,
+) -> Result<FirmwareUpdate, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let firmware_update_id = con
+    .query_one(
+      "INSERT INTO
+       firmware_update_t(
+        creation_time,
+        command_id,
+        firmware_update_kind,
+        transfer_offset,
+        previous_firmware_version
+       )
+       VALUES($1, $2, $3, $4, $5)
+       RETURNING firmware_update_id
+      ",
+      &[
+        &creation_time,
+        &command_id,
+        &(firmware_update_kind.clone() as i64),
+        &transfer_offset,
+        &previous_firmware_version,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return firmware update
+  Ok(FirmwareUpdate {
+    firmware_update_id,
+    creation_time,
+    command_id,
+    firmware_update_kind,
+    transfer_offset,
+    previous_firmware_version,
+  })
+}
+
+pub async fn get_by_command_id(
+  con: &mut impl GenericClient,
+  command_id: i64,
+) -> Result<Option<FirmwareUpdate>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM recent_firmware_update_v WHERE command_id=$1",
+      &[&command_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// the updates of a scanner that are currently in the given state
+pub async fn get_recent_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  firmware_update_kind: FirmwareUpdateKind,
+) -> Result<Vec<FirmwareUpdate>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT fu.* FROM recent_firmware_update_v fu
+       INNER JOIN command_t c ON c.command_id = fu.command_id
+       WHERE c.scanner_id = $1
+       AND fu.firmware_update_kind = $2
+       ORDER BY fu.firmware_update_id
+      ",
+      &[&scanner_id, &(firmware_update_kind as i64)],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+// only returns updates of scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::FirmwareUpdateViewProps,
+) -> Result<Vec<FirmwareUpdate>, tokio_postgres::Error> {
+  let sql = [
+    if props.only_recent {
+      "SELECT fu.* FROM recent_firmware_update_v fu"
+    } else {
+      "SELECT fu.* FROM firmware_update_t fu"
+    },
+    " INNER JOIN command_t c ON c.command_id = fu.command_id",
+    " INNER JOIN scanner_t s ON s.scanner_id = c.scanner_id",
+    " WHERE 1 = 1",
+    " AND ($1::bigint[] IS NULL OR fu.firmware_update_id = ANY($1))",
+    " AND ($2::bigint   IS NULL OR fu.creation_time >= $2)",
+    " AND ($3::bigint   IS NULL OR fu.creation_time <= $3)",
+    " AND ($4::bigint[] IS NULL OR fu.command_id = ANY($4))",
+    " AND ($5::text[]   IS NULL OR c.scanner_id = ANY($5))",
+    " AND ($6::bigint[] IS NULL OR c.firmware_id = ANY($6))",
+    " AND ($7::bigint   IS NULL OR fu.firmware_update_kind = $7)",
+    " AND s.creator_user_id = $8",
+    " ORDER BY fu.firmware_update_id",
+  ]
+  .join("\n");
+
+  let stmnt = con.prepare(&sql).await?;
+
+  let results = con
+    .query(
+      &stmnt,
+      &[
+        &props.firmware_update_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.command_id,
+        &props.scanner_id,
+        &props.firmware_id,
+        &props.firmware_update_kind.map(|x| x as i64),
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
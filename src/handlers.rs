@@ -1,16 +1,47 @@
 use std::error::Error;
 
 use super::Config;
+use super::Connections;
 use super::Db;
+use super::Feed;
 use auth_service_api::request;
 use auth_service_api::response;
+use cnc_service_api::request as cnc_request;
+use cnc_service_api::response as cnc_response;
 
 use super::api_key_service;
+use super::auth_card_data_service;
+use super::auth_card_service;
+use super::card_read_service;
+use super::command_ack_service;
+use super::command_delivery_service;
+use super::command_dispatcher;
+use super::command_service;
 use super::db_types::*;
+use super::device_protocol;
+use super::diagnostics_service;
 use super::email_service;
+use super::factory_key_service;
+use super::firmware_service;
+use super::firmware_update_service;
+use super::feed_socket;
 use super::parent_permission_service;
 use super::password_reset_service;
 use super::password_service;
+use super::rollout_data_service;
+use super::rollout_manager;
+use super::rollout_service;
+use super::scanner_cache_service;
+use super::scanner_config_ack_service;
+use super::scanner_config_service;
+use super::scanner_data_service;
+use super::scanner_key_revocation_service;
+use super::scanner_key_service;
+use super::scanner_log_service;
+use super::scanner_presence_service;
+use super::scanner_service;
+use super::scanner_socket;
+use super::telemetry_service;
 use super::user_data_service;
 use super::user_service;
 use super::utils;
@@ -21,6 +52,12 @@ use mail_service_api::response::MailError;
 
 static FIFTEEN_MINUTES: u64 = 15 * 60 * 1000;
 
+// how long a command waits for an offline scanner unless told otherwise
+static ONE_DAY: i64 = 24 * 60 * 60 * 1000;
+
+// the highest priority a command may be queued with
+static MAX_COMMAND_PRIORITY: i64 = 100;
+
 fn report_internal_err<E: std::error::Error>(e: E) -> response::AuthError {
   utils::log(utils::Event {
     msg: e.to_string(),
@@ -39,6 +76,19 @@ fn report_postgres_err(e: tokio_postgres::Error) -> response::AuthError {
   response::AuthError::InternalServerError
 }
 
+// for endpoints that check an api key, but otherwise fail with CncErrors
+fn auth_to_cnc_err(e: response::AuthError) -> cnc_response::CncError {
+  match e {
+    response::AuthError::InternalServerError => cnc_response::CncError::InternalServerError,
+    _ => cnc_response::CncError::ApiKeyUnauthorized,
+  }
+}
+
+fn report_cnc_postgres_err(e: tokio_postgres::Error) -> cnc_response::CncError {
+  report_postgres_err(e);
+  cnc_response::CncError::InternalServerError
+}
+
 fn report_mail_err(e: MailError) -> response::AuthError {
   let ae = match e {
     MailError::DestinationBounced => response::AuthError::EmailBounced,
@@ -192,6 +242,326 @@ async fn fill_verification_challenge(
   })
 }
 
+async fn fill_command(
+  con: &mut tokio_postgres::Client,
+  command: Command,
+) -> Result<cnc_response::Command, response::AuthError> {
+  let command_ack = command_ack_service::get_by_command_id(con, command.command_id)
+    .await
+    .map_err(report_postgres_err)?;
+
+  let delivery_count = command_delivery_service::count_by_command_id(con, command.command_id)
+    .await
+    .map_err(report_postgres_err)?;
+
+  let expired = utils::current_time_millis() > command.creation_time + command.duration;
+
+  let command_status = match (command_ack, expired, delivery_count) {
+    (Some(_), _, _) => cnc_response::CommandStatusKind::Acked,
+    (None, true, _) => cnc_response::CommandStatusKind::Expired,
+    (None, false, 0) => cnc_response::CommandStatusKind::Pending,
+    (None, false, n) if n >= command.max_attempts => cnc_response::CommandStatusKind::Failed,
+    (None, false, _) => cnc_response::CommandStatusKind::Sent,
+  };
+
+  Ok(cnc_response::Command {
+    command_id: command.command_id,
+    creation_time: command.creation_time,
+    creator_user_id: command.creator_user_id,
+    scanner_id: command.scanner_id,
+    command_kind: command.command_kind,
+    duration: command.duration,
+    priority: command.priority,
+    max_attempts: command.max_attempts,
+    firmware_id: command.firmware_id,
+    delivery_count,
+    command_status,
+  })
+}
+
+async fn fill_firmware(
+  _con: &mut tokio_postgres::Client,
+  firmware: Firmware,
+) -> Result<cnc_response::Firmware, response::AuthError> {
+  Ok(cnc_response::Firmware {
+    firmware_id: firmware.firmware_id,
+    creation_time: firmware.creation_time,
+    creator_user_id: firmware.creator_user_id,
+    firmware_version: firmware.firmware_version,
+    sha256: firmware.sha256,
+    size: firmware.size,
+  })
+}
+
+async fn fill_firmware_update(
+  _con: &mut tokio_postgres::Client,
+  firmware_update: FirmwareUpdate,
+) -> Result<cnc_response::FirmwareUpdate, response::AuthError> {
+  Ok(cnc_response::FirmwareUpdate {
+    firmware_update_id: firmware_update.firmware_update_id,
+    creation_time: firmware_update.creation_time,
+    command_id: firmware_update.command_id,
+    firmware_update_kind: firmware_update.firmware_update_kind,
+    transfer_offset: firmware_update.transfer_offset,
+  })
+}
+
+async fn fill_rollout(
+  con: &mut tokio_postgres::Client,
+  rollout: Rollout,
+) -> Result<cnc_response::Rollout, cnc_response::CncError> {
+  let rollout_data = rollout_data_service::get_by_rollout_id(con, rollout.rollout_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::RolloutDataNonexistent)?;
+
+  Ok(cnc_response::Rollout {
+    rollout_id: rollout.rollout_id,
+    creation_time: rollout.creation_time,
+    creator_user_id: rollout.creator_user_id,
+    firmware_id: rollout.firmware_id,
+    location_id: rollout.location_id,
+    stage_percentages: rollout.stage_percentages,
+    failure_threshold_percent: rollout.failure_threshold_percent,
+    stage: rollout_data.stage,
+    rollout_status_kind: rollout_data.rollout_status_kind,
+  })
+}
+
+async fn fill_auth_card(
+  con: &mut tokio_postgres::Client,
+  auth_card: AuthCard,
+) -> Result<cnc_response::AuthCard, cnc_response::CncError> {
+  let auth_card_data = auth_card_data_service::get_by_auth_card_id(con, &auth_card.auth_card_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::AuthCardDataNonexistent)?;
+
+  Ok(cnc_response::AuthCard {
+    auth_card_id: auth_card.auth_card_id,
+    creation_time: auth_card.creation_time,
+    creator_user_id: auth_card.creator_user_id,
+    school_id: auth_card.school_id,
+    description: auth_card_data.description,
+    active: auth_card_data.active,
+  })
+}
+
+async fn fill_auth_card_data(
+  _con: &mut tokio_postgres::Client,
+  auth_card_data: AuthCardData,
+) -> Result<cnc_response::AuthCardData, response::AuthError> {
+  Ok(cnc_response::AuthCardData {
+    auth_card_data_id: auth_card_data.auth_card_data_id,
+    creation_time: auth_card_data.creation_time,
+    creator_user_id: auth_card_data.creator_user_id,
+    auth_card_id: auth_card_data.auth_card_id,
+    description: auth_card_data.description,
+    active: auth_card_data.active,
+  })
+}
+
+// a command must be able to outlive its queueing, and its priority must be in range
+fn check_command_schedule(
+  duration: Option<i64>,
+  priority: Option<i64>,
+) -> Result<(), cnc_response::CncError> {
+  let schedule_valid = duration.map_or(true, |x| x > 0)
+    && priority.map_or(true, |x| (0..=MAX_COMMAND_PRIORITY).contains(&x));
+
+  if !schedule_valid {
+    return Err(cnc_response::CncError::CommandInvalid);
+  }
+
+  Ok(())
+}
+
+// FLASH commands need firmware to flash, which must be the scanner owner's.
+// Other commands don't take any.
+async fn check_command_firmware(
+  con: &mut tokio_postgres::Client,
+  creator_user_id: i64,
+  command_kind: &cnc_request::CommandKind,
+  firmware_id: Option<i64>,
+) -> Result<Option<i64>, cnc_response::CncError> {
+  match (command_kind, firmware_id) {
+    (cnc_request::CommandKind::Flash, Some(firmware_id)) => {
+      firmware_service::get_by_firmware_id(con, firmware_id)
+        .await
+        .map_err(report_cnc_postgres_err)?
+        .filter(|x| x.creator_user_id == creator_user_id)
+        .ok_or(cnc_response::CncError::FirmwareNonexistent)?;
+      Ok(Some(firmware_id))
+    }
+    (cnc_request::CommandKind::Flash, None) => Err(cnc_response::CncError::FirmwareNonexistent),
+    (_, _) => Ok(None),
+  }
+}
+
+async fn fill_device(
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  scanner: Scanner,
+) -> Result<cnc_response::Device, cnc_response::CncError> {
+  let scanner_data = scanner_data_service::get_by_scanner_id(con, &scanner.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::DeviceDataNonexistent)?;
+
+  let live_last_seen_time = connections
+    .lock()
+    .await
+    .get(&scanner.scanner_id)
+    .map(|x| x.last_seen_time);
+
+  // if it isn't connected, the best we know is when it last read a card
+  let last_seen_time = match live_last_seen_time {
+    Some(time) => Some(time),
+    None => card_read_service::get_latest_time_by_scanner_id(con, &scanner.scanner_id)
+      .await
+      .map_err(report_cnc_postgres_err)?,
+  };
+
+  Ok(cnc_response::Device {
+    device_id: scanner.scanner_id,
+    creation_time: scanner.creation_time,
+    creator_user_id: scanner.creator_user_id,
+    location_id: scanner_data.location_id,
+    description: scanner_data.description,
+    active: scanner_data.active,
+    connected: live_last_seen_time.is_some(),
+    last_seen_time,
+  })
+}
+
+async fn fill_scanner_stats(
+  _con: &mut tokio_postgres::Client,
+  scanner_cache: ScannerCache,
+) -> Result<cnc_response::ScannerStats, response::AuthError> {
+  Ok(cnc_response::ScannerStats {
+    scanner_id: scanner_cache.scanner_id,
+    creation_time: scanner_cache.creation_time,
+    average_ping_ms: scanner_cache.average_ping_ms,
+    lifetime_uses_count: scanner_cache.lifetime_uses_count,
+    month_uses_count: scanner_cache.month_uses_count,
+  })
+}
+
+async fn fill_scanner_key(
+  con: &mut tokio_postgres::Client,
+  scanner_key: ScannerKey,
+) -> Result<cnc_response::ScannerKey, response::AuthError> {
+  let revocation =
+    scanner_key_revocation_service::get_by_scanner_key_id(con, scanner_key.scanner_key_id)
+      .await
+      .map_err(report_postgres_err)?;
+
+  Ok(cnc_response::ScannerKey {
+    scanner_key_id: scanner_key.scanner_key_id,
+    creation_time: scanner_key.creation_time,
+    scanner_id: scanner_key.scanner_id,
+    duration: scanner_key.duration,
+    last_used_time: scanner_key.last_used_time,
+    revocation_time: revocation.map(|x| x.creation_time),
+  })
+}
+
+async fn fill_scanner_config(
+  con: &mut tokio_postgres::Client,
+  scanner_config: ScannerConfig,
+) -> Result<cnc_response::ScannerConfig, response::AuthError> {
+  let scanner_config_ack =
+    scanner_config_ack_service::get_by_scanner_config_id(con, scanner_config.scanner_config_id)
+      .await
+      .map_err(report_postgres_err)?;
+
+  Ok(cnc_response::ScannerConfig {
+    scanner_config_id: scanner_config.scanner_config_id,
+    creation_time: scanner_config.creation_time,
+    creator_user_id: scanner_config.creator_user_id,
+    scanner_id: scanner_config.scanner_id,
+    beep_volume: scanner_config.beep_volume,
+    led_brightness: scanner_config.led_brightness,
+    read_debounce: scanner_config.read_debounce,
+    applied_time: scanner_config_ack.map(|x| x.creation_time),
+  })
+}
+
+async fn fill_telemetry(
+  _con: &mut tokio_postgres::Client,
+  telemetry: Telemetry,
+) -> Result<cnc_response::Telemetry, response::AuthError> {
+  Ok(cnc_response::Telemetry {
+    telemetry_id: telemetry.telemetry_id,
+    creation_time: telemetry.creation_time,
+    scanner_id: telemetry.scanner_id,
+    resolution: telemetry.resolution,
+    sample_count: telemetry.sample_count,
+    uptime: telemetry.uptime,
+    free_heap: telemetry.free_heap,
+    wifi_rssi: telemetry.wifi_rssi,
+    temperature: telemetry.temperature,
+    reader_errors: telemetry.reader_errors,
+  })
+}
+
+async fn fill_scanner_log(
+  _con: &mut tokio_postgres::Client,
+  scanner_log: ScannerLog,
+) -> Result<cnc_response::ScannerLog, response::AuthError> {
+  Ok(cnc_response::ScannerLog {
+    scanner_log_id: scanner_log.scanner_log_id,
+    creation_time: scanner_log.creation_time,
+    scanner_id: scanner_log.scanner_id,
+    severity_kind: match scanner_log.severity_kind {
+      utils::SeverityKind::Info => cnc_request::SeverityKind::Info,
+      utils::SeverityKind::Warning => cnc_request::SeverityKind::Warning,
+      utils::SeverityKind::Error => cnc_request::SeverityKind::Error,
+      utils::SeverityKind::Fatal => cnc_request::SeverityKind::Fatal,
+    },
+    msg: scanner_log.msg,
+  })
+}
+
+async fn fill_diagnostics(
+  _con: &mut tokio_postgres::Client,
+  diagnostics: Diagnostics,
+) -> Result<cnc_response::Diagnostics, response::AuthError> {
+  Ok(cnc_response::Diagnostics {
+    diagnostics_id: diagnostics.diagnostics_id,
+    creation_time: diagnostics.creation_time,
+    command_id: diagnostics.command_id,
+    scanner_id: diagnostics.scanner_id,
+    size: diagnostics.size,
+  })
+}
+
+async fn fill_scanner_presence(
+  _con: &mut tokio_postgres::Client,
+  scanner_presence: ScannerPresence,
+) -> Result<cnc_response::ScannerPresence, response::AuthError> {
+  Ok(cnc_response::ScannerPresence {
+    scanner_presence_id: scanner_presence.scanner_presence_id,
+    creation_time: scanner_presence.creation_time,
+    scanner_id: scanner_presence.scanner_id,
+    connected: scanner_presence.connected,
+  })
+}
+
+async fn fill_scanner_uptime(
+  _con: &mut tokio_postgres::Client,
+  scanner_uptime: ScannerUptime,
+  min_time: i64,
+  max_time: i64,
+) -> Result<cnc_response::ScannerUptime, response::AuthError> {
+  Ok(cnc_response::ScannerUptime {
+    scanner_id: scanner_uptime.scanner_id,
+    min_time,
+    max_time,
+    connected_duration: scanner_uptime.connected_duration,
+  })
+}
+
 pub async fn get_api_key_if_valid_noverify(
   con: &mut tokio_postgres::Client,
   api_key: &str,
@@ -227,6 +597,8 @@ pub async fn api_key_new_valid(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::ApiKeyNewValidProps,
 ) -> Result<response::ApiKey, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -274,6 +646,8 @@ pub async fn api_key_new_cancel(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::ApiKeyNewCancelProps,
 ) -> Result<response::ApiKey, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -377,6 +751,8 @@ pub async fn verification_challenge_new(
   config: Config,
   db: Db,
   mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::VerificationChallengeNewProps,
 ) -> Result<response::VerificationChallenge, response::AuthError> {
   // avoid sending email to obviously bad addresses
@@ -460,6 +836,8 @@ pub async fn user_new(
   config: Config,
   db: Db,
   mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::UserNewProps,
 ) -> Result<response::UserData, response::AuthError> {
   // name isn't empty
@@ -557,6 +935,8 @@ pub async fn user_data_new(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::UserDataNewProps,
 ) -> Result<response::UserData, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -577,6 +957,8 @@ pub async fn email_new(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::EmailNewProps,
 ) -> Result<response::Email, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -630,6 +1012,8 @@ pub async fn parent_permission_new(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::ParentPermissionNewProps,
 ) -> Result<response::ParentPermission, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -674,6 +1058,8 @@ pub async fn password_reset_new(
   config: Config,
   db: Db,
   mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::PasswordResetNewProps,
 ) -> Result<response::PasswordReset, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -724,6 +1110,8 @@ pub async fn password_new_reset(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::PasswordNewResetProps,
 ) -> Result<response::Password, response::AuthError> {
   // no api key verification needed
@@ -781,6 +1169,8 @@ pub async fn password_new_change(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::PasswordNewChangeProps,
 ) -> Result<response::Password, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -818,6 +1208,8 @@ pub async fn user_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::UserViewProps,
 ) -> Result<Vec<response::User>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -840,6 +1232,8 @@ pub async fn user_data_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::UserDataViewProps,
 ) -> Result<Vec<response::UserData>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -862,6 +1256,8 @@ pub async fn email_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::EmailViewProps,
 ) -> Result<Vec<response::Email>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -885,6 +1281,8 @@ pub async fn password_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::PasswordViewProps,
 ) -> Result<Vec<response::Password>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -908,6 +1306,8 @@ pub async fn parent_permission_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::ParentPermissionViewProps,
 ) -> Result<Vec<response::ParentPermission>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -930,6 +1330,8 @@ pub async fn verification_challenge_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::VerificationChallengeViewProps,
 ) -> Result<Vec<response::VerificationChallenge>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -952,6 +1354,8 @@ pub async fn api_key_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::ApiKeyViewProps,
 ) -> Result<Vec<response::ApiKey>, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -971,11 +1375,180 @@ pub async fn api_key_view(
   Ok(resp_api_keys)
 }
 
+fn report_register_fail(uid: &str, reason: &str) -> device_protocol::RegisterResponse {
+  utils::log(utils::Event {
+    msg: format!("scanner registration rejected: {}", reason),
+    source: Some(format!("scanner uid: {}", uid)),
+    severity: utils::SeverityKind::Warning,
+  });
+  device_protocol::RegisterResponse::RegisterFail
+}
+
+// sent by a device in pairing mode to attach itself to the supervisor card's account
+pub async fn register(
+  config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  feed: Feed,
+  props: device_protocol::RegisterProps,
+) -> Result<device_protocol::RegisterResponse, response::AuthError> {
+  let con = &mut *db.lock().await;
+
+  // the supervisor card must exist
+  let auth_card = match auth_card_service::get_by_auth_card_id(con, &props.supervisor_card_id)
+    .await
+    .map_err(report_postgres_err)?
+  {
+    Some(auth_card) => auth_card,
+    None => return Ok(report_register_fail(&props.uid, "supervisor card nonexistent")),
+  };
+
+  // the supervisor card must not have been deactivated
+  let auth_card_active = auth_card_data_service::get_by_auth_card_id(con, &auth_card.auth_card_id)
+    .await
+    .map_err(report_postgres_err)?
+    .map_or(false, |x| x.active);
+
+  if !auth_card_active {
+    return Ok(report_register_fail(&props.uid, "supervisor card inactive"));
+  }
+
+  // a scanner may only be registered once
+  if scanner_service::exists_by_scanner_id(con, &props.uid)
+    .await
+    .map_err(report_postgres_err)?
+  {
+    return Ok(report_register_fail(&props.uid, "scanner already registered"));
+  }
+
+  // otherwise the scanner couldn't prove who it is at STARTUP
+  if !config.allow_legacy_scanners
+    && factory_key_service::get_by_uid(con, &props.uid)
+      .await
+      .map_err(report_postgres_err)?
+      .is_none()
+  {
+    return Ok(report_register_fail(&props.uid, "no factory key"));
+  }
+
+  let mut sp = con.transaction().await.map_err(report_postgres_err)?;
+
+  // create scanner
+  let scanner = scanner_service::add(
+    &mut sp,
+    props.uid,
+    auth_card.creator_user_id,
+    auth_card.auth_card_id,
+  )
+  .await
+  .map_err(report_postgres_err)?;
+
+  // create initial scanner data
+  scanner_data_service::add(
+    &mut sp,
+    scanner.creator_user_id,
+    scanner.scanner_id.clone(),
+    None,
+    String::new(),
+    true,
+  )
+  .await
+  .map_err(report_postgres_err)?;
+
+  sp.commit().await.map_err(report_postgres_err)?;
+
+  // let listening microservices know about the new scanner
+  feed_socket::initialize(&feed, scanner).await;
+
+  Ok(device_protocol::RegisterResponse::RegisterSuccess)
+}
+
+pub async fn command_new(
+  config: Config,
+  db: Db,
+  _mail_service: MailService,
+  connections: Connections,
+  _feed: Feed,
+  props: cnc_request::CommandNewProps,
+) -> Result<cnc_response::Command, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  // only the owner of a scanner may command it
+  let scanner = scanner_service::get_by_scanner_id(con, &props.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::ApiKeyUnauthorized)?;
+
+  check_command_schedule(props.duration, props.priority)?;
+
+  let firmware_id = check_command_firmware(
+    con,
+    creator_key.creator_user_id,
+    &props.command_kind,
+    props.firmware_id,
+  )
+  .await?;
+
+  let policy = config.command_policies.get(&props.command_kind);
+
+  let command = command_service::add(
+    con,
+    creator_key.creator_user_id,
+    scanner.scanner_id,
+    props.command_kind.clone(),
+    props.duration.unwrap_or(ONE_DAY),
+    props.priority.unwrap_or(policy.priority),
+    policy.max_attempts,
+    firmware_id,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  // sent right away if the scanner is connected, otherwise it's sent on its next STARTUP
+  command_dispatcher::wake(&connections, &command.scanner_id).await;
+
+  fill_command(con, command).await.map_err(auth_to_cnc_err)
+}
+
+pub async fn command_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::CommandViewProps,
+) -> Result<Vec<cnc_response::Command>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get commands
+  let commands = command_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_commands = vec![];
+  for u in commands.into_iter() {
+    resp_commands.push(fill_command(con, u).await?);
+  }
+
+  Ok(resp_commands)
+}
+
 // special internal api
 pub async fn get_user_by_id(
   _config: Config,
   db: Db,
   _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
   props: request::GetUserByIdProps,
 ) -> Result<response::User, response::AuthError> {
   let con = &mut *db.lock().await;
@@ -988,20 +1561,829 @@ pub async fn get_user_by_id(
   fill_user(con, user).await
 }
 
-pub async fn get_user_by_api_key_if_valid(
+pub async fn scanner_stats_view(
   _config: Config,
   db: Db,
   _mail_service: MailService,
-  props: request::GetUserByApiKeyIfValid,
-) -> Result<response::User, response::AuthError> {
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerStatsViewProps,
+) -> Result<Vec<cnc_response::ScannerStats>, response::AuthError> {
   let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // these are cached, so this never has to count card reads
+  let scanner_caches = scanner_cache_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
 
-  let api_key = get_api_key_if_verified(con, &props.api_key).await?;
+  // return
+  let mut resp_scanner_stats = vec![];
+  for u in scanner_caches.into_iter() {
+    resp_scanner_stats.push(fill_scanner_stats(con, u).await?);
+  }
 
-  let user = user_service::get_by_user_id(con, api_key.creator_user_id)
+  Ok(resp_scanner_stats)
+}
+
+pub async fn scanner_config_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerConfigNewProps,
+) -> Result<cnc_response::ScannerConfig, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
     .await
-    .map_err(report_postgres_err)?
-    .ok_or(response::AuthError::UserNonexistent)?;
+    .map_err(auth_to_cnc_err)?;
 
-  fill_user(con, user).await
+  // only the owner of a scanner may configure it
+  let scanner = scanner_service::get_by_scanner_id(con, &props.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::ApiKeyUnauthorized)?;
+
+  let config_valid = (0..=100).contains(&props.beep_volume)
+    && (0..=100).contains(&props.led_brightness)
+    && props.read_debounce >= 0;
+
+  if !config_valid {
+    return Err(cnc_response::CncError::ScannerConfigInvalid);
+  }
+
+  let scanner_config = scanner_config_service::add(
+    con,
+    creator_key.creator_user_id,
+    scanner.scanner_id,
+    props.beep_volume,
+    props.led_brightness,
+    props.read_debounce,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  // otherwise it's sent on the scanner's next STARTUP
+  scanner_socket::push_config(con, &connections, &scanner_config.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?;
+
+  fill_scanner_config(con, scanner_config)
+    .await
+    .map_err(auth_to_cnc_err)
+}
+
+pub async fn scanner_config_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerConfigViewProps,
+) -> Result<Vec<cnc_response::ScannerConfig>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get scanner configs
+  let scanner_configs = scanner_config_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_scanner_configs = vec![];
+  for u in scanner_configs.into_iter() {
+    resp_scanner_configs.push(fill_scanner_config(con, u).await?);
+  }
+
+  Ok(resp_scanner_configs)
+}
+
+pub async fn telemetry_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::TelemetryViewProps,
+) -> Result<Vec<cnc_response::Telemetry>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get telemetry
+  let telemetries = telemetry_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_telemetries = vec![];
+  for u in telemetries.into_iter() {
+    resp_telemetries.push(fill_telemetry(con, u).await?);
+  }
+
+  Ok(resp_telemetries)
+}
+
+pub async fn scanner_log_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerLogViewProps,
+) -> Result<Vec<cnc_response::ScannerLog>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get scanner logs
+  let scanner_logs = scanner_log_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_scanner_logs = vec![];
+  for u in scanner_logs.into_iter() {
+    resp_scanner_logs.push(fill_scanner_log(con, u).await?);
+  }
+
+  Ok(resp_scanner_logs)
+}
+
+pub async fn diagnostics_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::DiagnosticsViewProps,
+) -> Result<Vec<cnc_response::Diagnostics>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get diagnostics
+  let diagnostics = diagnostics_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_diagnostics = vec![];
+  for u in diagnostics.into_iter() {
+    resp_diagnostics.push(fill_diagnostics(con, u).await?);
+  }
+
+  Ok(resp_diagnostics)
+}
+
+// the data is sent as base64
+pub async fn diagnostics_download(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::DiagnosticsDownloadProps,
+) -> Result<String, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  let diagnostics = diagnostics_service::get_by_diagnostics_id(con, props.diagnostics_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::DiagnosticsNonexistent)?;
+
+  // only the owner of a scanner may download its diagnostics
+  scanner_service::get_by_scanner_id(con, &diagnostics.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::ApiKeyUnauthorized)?;
+
+  let diagnostics_data = diagnostics_service::get_data_by_diagnostics_id(con, diagnostics.diagnostics_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::DiagnosticsNonexistent)?;
+
+  Ok(base64_url::encode(&diagnostics_data))
+}
+
+// with only_recent, this is whether each scanner is online right now
+// otherwise it's the history of every connect and disconnect
+pub async fn scanner_presence_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerPresenceViewProps,
+) -> Result<Vec<cnc_response::ScannerPresence>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get presences
+  let scanner_presences = scanner_presence_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_scanner_presences = vec![];
+  for u in scanner_presences.into_iter() {
+    resp_scanner_presences.push(fill_scanner_presence(con, u).await?);
+  }
+
+  Ok(resp_scanner_presences)
+}
+
+// how long each of the caller's scanners was online between min_time and max_time (now if unset)
+pub async fn scanner_uptime_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerUptimeViewProps,
+) -> Result<Vec<cnc_response::ScannerUptime>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+
+  let max_time = props.max_time.unwrap_or_else(utils::current_time_millis);
+  let min_time = props.min_time.min(max_time);
+
+  let scanner_uptimes = scanner_presence_service::get_uptime(
+    con,
+    creator_key.creator_user_id,
+    props.scanner_id,
+    min_time,
+    max_time,
+  )
+  .await
+  .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_scanner_uptimes = vec![];
+  for u in scanner_uptimes.into_iter() {
+    resp_scanner_uptimes.push(fill_scanner_uptime(con, u, min_time, max_time).await?);
+  }
+
+  Ok(resp_scanner_uptimes)
+}
+
+// sends the scanner a new api key, revoking the old ones once the scanner acks it
+pub async fn scanner_key_rotate(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerKeyRotateProps,
+) -> Result<cnc_response::ScannerKey, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  // only the owner of a scanner may rotate its keys
+  let scanner = scanner_service::get_by_scanner_id(con, &props.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::ApiKeyUnauthorized)?;
+
+  if !connections.lock().await.contains_key(&scanner.scanner_id) {
+    return Err(cnc_response::CncError::DeviceOffline);
+  }
+
+  let scanner_key = scanner_socket::rotate_key(con, &connections, &scanner.scanner_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::DeviceUnsupported)?;
+
+  fill_scanner_key(con, scanner_key)
+    .await
+    .map_err(auth_to_cnc_err)
+}
+
+pub async fn scanner_key_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::ScannerKeyViewProps,
+) -> Result<Vec<cnc_response::ScannerKey>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get scanner keys
+  let scanner_keys = scanner_key_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_scanner_keys = vec![];
+  for u in scanner_keys.into_iter() {
+    resp_scanner_keys.push(fill_scanner_key(con, u).await?);
+  }
+
+  Ok(resp_scanner_keys)
+}
+
+pub async fn firmware_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::FirmwareNewProps,
+) -> Result<cnc_response::Firmware, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  // the image is sent as base64
+  let firmware_data = base64_url::decode(&props.firmware_data)
+    .map_err(|_| cnc_response::CncError::FirmwareDataMalformed)?;
+
+  // scanners report their version at STARTUP, so it has to identify the owner's firmware
+  if firmware_service::get_by_creator_user_id_and_firmware_version(
+    con,
+    creator_key.creator_user_id,
+    &props.firmware_version,
+  )
+  .await
+    .map_err(report_cnc_postgres_err)?
+    .is_some()
+  {
+    return Err(cnc_response::CncError::FirmwareVersionExists);
+  }
+
+  let firmware = firmware_service::add(
+    con,
+    creator_key.creator_user_id,
+    props.firmware_version,
+    firmware_data,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  fill_firmware(con, firmware).await.map_err(auth_to_cnc_err)
+}
+
+pub async fn firmware_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::FirmwareViewProps,
+) -> Result<Vec<cnc_response::Firmware>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get firmwares
+  let firmwares = firmware_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_firmwares = vec![];
+  for u in firmwares.into_iter() {
+    resp_firmwares.push(fill_firmware(con, u).await?);
+  }
+
+  Ok(resp_firmwares)
+}
+
+pub async fn firmware_update_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::FirmwareUpdateViewProps,
+) -> Result<Vec<cnc_response::FirmwareUpdate>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get firmware updates
+  let firmware_updates = firmware_update_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_firmware_updates = vec![];
+  for u in firmware_updates.into_iter() {
+    resp_firmware_updates.push(fill_firmware_update(con, u).await?);
+  }
+
+  Ok(resp_firmware_updates)
+}
+
+pub async fn rollout_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::RolloutNewProps,
+) -> Result<cnc_response::Rollout, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  // each stage has to flash at least as many scanners as the last, and the last should flash all of them
+  let stages_valid = !props.stage_percentages.is_empty()
+    && props.stage_percentages.iter().all(|x| (1..=100).contains(x))
+    && props.stage_percentages.windows(2).all(|x| x[0] <= x[1]);
+
+  if !stages_valid || !(0..=100).contains(&props.failure_threshold_percent) {
+    return Err(cnc_response::CncError::RolloutInvalid);
+  }
+
+  // only the user who uploaded the firmware may roll it out
+  firmware_service::get_by_firmware_id(con, props.firmware_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::FirmwareNonexistent)?;
+
+  let mut sp = con.transaction().await.map_err(report_cnc_postgres_err)?;
+
+  let rollout = rollout_service::add(
+    &mut sp,
+    creator_key.creator_user_id,
+    props.firmware_id,
+    props.location_id,
+    props.stage_percentages,
+    props.failure_threshold_percent,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  // the rollout manager picks it up from here
+  rollout_data_service::add(&mut sp, rollout.rollout_id, 0, cnc_request::RolloutStatusKind::Running)
+    .await
+    .map_err(report_cnc_postgres_err)?;
+
+  sp.commit().await.map_err(report_cnc_postgres_err)?;
+
+  fill_rollout(con, rollout).await
+}
+
+// halts or resumes a rollout
+pub async fn rollout_data_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::RolloutDataNewProps,
+) -> Result<cnc_response::Rollout, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  // only the creator of a rollout may change it
+  let rollout = rollout_service::get_by_rollout_id(con, props.rollout_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::RolloutNonexistent)?;
+
+  let rollout_data = rollout_data_service::get_by_rollout_id(con, rollout.rollout_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .ok_or(cnc_response::CncError::RolloutDataNonexistent)?;
+
+  if !rollout_manager::manual_transition_allowed(
+    &rollout_data.rollout_status_kind,
+    &props.rollout_status_kind,
+  ) {
+    return Err(cnc_response::CncError::RolloutInvalid);
+  }
+
+  // a resumed rollout carries on from the stage it was at
+  rollout_data_service::add(
+    con,
+    rollout.rollout_id,
+    rollout_data.stage,
+    props.rollout_status_kind,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  fill_rollout(con, rollout).await
+}
+
+pub async fn rollout_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::RolloutViewProps,
+) -> Result<Vec<cnc_response::Rollout>, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+  // get rollouts
+  let rollouts = rollout_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_cnc_postgres_err)?;
+
+  // return
+  let mut resp_rollouts = vec![];
+  for u in rollouts.into_iter() {
+    resp_rollouts.push(fill_rollout(con, u).await?);
+  }
+
+  Ok(resp_rollouts)
+}
+
+pub async fn auth_card_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::AuthCardNewProps,
+) -> Result<cnc_response::AuthCard, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  let mut sp = con.transaction().await.map_err(report_cnc_postgres_err)?;
+
+  // the id is the card's own, so it may only be added once
+  if auth_card_service::get_by_auth_card_id(&mut sp, &props.auth_card_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .is_some()
+  {
+    return Err(cnc_response::CncError::AuthCardExists);
+  }
+
+  let auth_card = auth_card_service::add(
+    &mut sp,
+    props.auth_card_id,
+    creator_key.creator_user_id,
+    props.school_id,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  // new cards start out active
+  auth_card_data_service::add(
+    &mut sp,
+    creator_key.creator_user_id,
+    auth_card.auth_card_id.clone(),
+    props.description,
+    true,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  sp.commit().await.map_err(report_cnc_postgres_err)?;
+
+  fill_auth_card(con, auth_card).await
+}
+
+// changes the description of an auth card, or deactivates and reactivates it
+pub async fn auth_card_data_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::AuthCardDataNewProps,
+) -> Result<cnc_response::AuthCard, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+
+  // only the creator of an auth card may change it
+  let auth_card = auth_card_service::get_by_auth_card_id(con, &props.auth_card_id)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .filter(|x| x.creator_user_id == creator_key.creator_user_id)
+    .ok_or(cnc_response::CncError::AuthCardNonexistent)?;
+
+  auth_card_data_service::add(
+    con,
+    creator_key.creator_user_id,
+    auth_card.auth_card_id.clone(),
+    props.description,
+    props.active,
+  )
+  .await
+  .map_err(report_cnc_postgres_err)?;
+
+  fill_auth_card(con, auth_card).await
+}
+
+pub async fn auth_card_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::AuthCardViewProps,
+) -> Result<Vec<cnc_response::AuthCard>, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key)
+    .await
+    .map_err(auth_to_cnc_err)?;
+  // get auth cards, only the caller's own are visible
+  let auth_cards = auth_card_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_cnc_postgres_err)?;
+
+  // return
+  let mut resp_auth_cards = vec![];
+  for u in auth_cards.into_iter() {
+    resp_auth_cards.push(fill_auth_card(con, u).await?);
+  }
+
+  Ok(resp_auth_cards)
+}
+
+pub async fn auth_card_data_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::AuthCardDataViewProps,
+) -> Result<Vec<cnc_response::AuthCardData>, response::AuthError> {
+  let con = &mut *db.lock().await;
+  // api key verification required
+  let creator_key = get_api_key_if_valid_noverify(con, &props.api_key).await?;
+  // get auth card data, only the history of the caller's own cards is visible
+  let auth_card_datas = auth_card_data_service::query(con, creator_key.creator_user_id, props)
+    .await
+    .map_err(report_postgres_err)?;
+
+  // return
+  let mut resp_auth_card_datas = vec![];
+  for u in auth_card_datas.into_iter() {
+    resp_auth_card_datas.push(fill_auth_card_data(con, u).await?);
+  }
+
+  Ok(resp_auth_card_datas)
+}
+
+// forwards a command from another microservice and waits for the scanner to ack it
+pub async fn command(
+  config: Config,
+  db: Db,
+  _mail_service: MailService,
+  connections: Connections,
+  _feed: Feed,
+  props: cnc_request::CommandProps,
+) -> Result<(), cnc_response::CncError> {
+  let command = {
+    let con = &mut *db.lock().await;
+
+    let scanner = scanner_service::get_by_scanner_id(con, &props.device_id)
+      .await
+      .map_err(report_cnc_postgres_err)?
+      .ok_or(cnc_response::CncError::DeviceNonexistent)?;
+
+    // the scanner would only ever get it after starting up with newer firmware
+    if let Some(connection) = connections.lock().await.get(&scanner.scanner_id) {
+      if !device_protocol::command_allowed(&props.command_kind, connection.protocol_version) {
+        return Err(cnc_response::CncError::DeviceUnsupported);
+      }
+    }
+
+    check_command_schedule(props.duration, props.priority)?;
+
+    let firmware_id = check_command_firmware(
+      con,
+      scanner.creator_user_id,
+      &props.command_kind,
+      props.firmware_id,
+    )
+    .await?;
+
+    let policy = config.command_policies.get(&props.command_kind);
+
+    command_service::add(
+      con,
+      scanner.creator_user_id,
+      scanner.scanner_id,
+      props.command_kind.clone(),
+      props.duration.unwrap_or(ONE_DAY),
+      props.priority.unwrap_or(policy.priority),
+      policy.max_attempts,
+      firmware_id,
+    )
+    .await
+    .map_err(report_cnc_postgres_err)?
+  };
+
+  // the db must not stay locked while we wait on the scanner
+  // if the scanner doesn't ack in time, the command stays queued until it expires
+  if !command_dispatcher::deliver_and_wait(&config, &db, &connections, &command).await {
+    return Err(cnc_response::CncError::DeviceTimedOut);
+  }
+
+  Ok(())
+}
+
+// lets other microservices see which scanners are registered
+pub async fn device_view(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  connections: Connections,
+  _feed: Feed,
+  props: cnc_request::DeviceViewProps,
+) -> Result<Vec<cnc_response::Device>, cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  let scanners = scanner_service::query(con, props)
+    .await
+    .map_err(report_cnc_postgres_err)?;
+
+  let mut resp_devices = vec![];
+  for u in scanners.into_iter() {
+    resp_devices.push(fill_device(con, &connections, u).await?);
+  }
+
+  Ok(resp_devices)
+}
+
+pub async fn get_user_by_api_key_if_valid(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: request::GetUserByApiKeyIfValid,
+) -> Result<response::User, response::AuthError> {
+  let con = &mut *db.lock().await;
+
+  let api_key = get_api_key_if_verified(con, &props.api_key).await?;
+
+  let user = user_service::get_by_user_id(con, api_key.creator_user_id)
+    .await
+    .map_err(report_postgres_err)?
+    .ok_or(response::AuthError::UserNonexistent)?;
+
+  fill_user(con, user).await
+}
+
+// called by the manufacturing line once it has primed a scanner with its secret
+pub async fn factory_key_new(
+  _config: Config,
+  db: Db,
+  _mail_service: MailService,
+  _connections: Connections,
+  _feed: Feed,
+  props: cnc_request::FactoryKeyNewProps,
+) -> Result<(), cnc_response::CncError> {
+  let con = &mut *db.lock().await;
+
+  // a scanner's secret can't be replaced, or anyone could take over the scanner
+  if factory_key_service::get_by_uid(con, &props.uid)
+    .await
+    .map_err(report_cnc_postgres_err)?
+    .is_some()
+  {
+    return Err(cnc_response::CncError::FactoryKeyExists);
+  }
+
+  factory_key_service::add(con, props.uid, props.secret_key)
+    .await
+    .map_err(report_cnc_postgres_err)?;
+
+  Ok(())
 }
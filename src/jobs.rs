@@ -0,0 +1,188 @@
+use std::error::Error;
+use std::time::Duration;
+
+use super::card_read_service;
+use super::db_types::ScannerUsage;
+use super::scanner_cache_service;
+use super::scanner_key_service;
+use super::scanner_service;
+use super::telemetry_service;
+use super::utils;
+use super::Connections;
+use super::Db;
+
+// Background tasks that keep scanner_cache_t, scanner_key_t and telemetry_t up to date.
+// They are spawned once at startup and run forever.
+
+// how often the rolling ping averages are written to scanner_cache_t
+static PING_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+// how long superseded rows of scanner_cache_t are kept
+static SCANNER_CACHE_HISTORY: i64 = 24 * 60 * 60 * 1000;
+
+// how often old telemetry is merged
+static TELEMETRY_DOWNSAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+static ONE_HOUR: i64 = 60 * 60 * 1000;
+static ONE_DAY: i64 = 24 * 60 * 60 * 1000;
+static THIRTY_DAYS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+fn report_postgres_err(e: tokio_postgres::Error) {
+  utils::log(utils::Event {
+    msg: e.to_string(),
+    source: e.source().map(|e| e.to_string()),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+pub async fn persist_ping(db: Db, connections: Connections) {
+  let mut interval = tokio::time::interval(PING_PERSIST_INTERVAL);
+  loop {
+    interval.tick().await;
+
+    // copy the averages out, so the registry isn't locked while we write
+    let (averages, scanner_key_ids): (Vec<(String, i64)>, Vec<i64>) = {
+      let connections = connections.lock().await;
+      (
+        connections
+          .iter()
+          .filter_map(|(scanner_id, x)| x.average_ping_ms().map(|avg| (scanner_id.clone(), avg)))
+          .collect(),
+        connections.values().map(|x| x.scanner_key_id).collect(),
+      )
+    };
+
+    let con = &mut *db.lock().await;
+
+    // keys in use by a connected scanner are still being used, even if it hasn't restarted in a while
+    if let Err(e) = scanner_key_service::touch_all(con, &scanner_key_ids).await {
+      report_postgres_err(e);
+    }
+    for (scanner_id, average_ping_ms) in averages {
+      if let Err(e) = persist_scanner_ping(con, scanner_id, average_ping_ms).await {
+        report_postgres_err(e);
+      }
+    }
+
+    // only the most recent cache of each scanner is ever read
+    if let Err(e) =
+      scanner_cache_service::prune(con, utils::current_time_millis() - SCANNER_CACHE_HISTORY).await
+    {
+      report_postgres_err(e);
+    }
+  }
+}
+
+async fn persist_scanner_ping(
+  con: &mut tokio_postgres::Client,
+  scanner_id: String,
+  average_ping_ms: i64,
+) -> Result<(), tokio_postgres::Error> {
+  let scanner = match scanner_service::get_by_scanner_id(con, &scanner_id).await? {
+    Some(scanner) => scanner,
+    None => return Ok(()),
+  };
+
+  // the usage counts are carried over from the last time they were computed
+  let previous = scanner_cache_service::get_by_scanner_id(con, &scanner_id).await?;
+
+  if previous.as_ref().map_or(false, |x| x.average_ping_ms == average_ping_ms) {
+    return Ok(());
+  }
+
+  scanner_cache_service::add(
+    con,
+    scanner.creator_user_id,
+    scanner_id,
+    average_ping_ms,
+    previous.as_ref().map_or(0, |x| x.lifetime_uses_count),
+    previous.as_ref().map_or(0, |x| x.month_uses_count),
+  )
+  .await?;
+
+  Ok(())
+}
+
+// recounts how often every scanner has been used
+pub async fn recompute_usage(db: Db, recompute_interval: Duration) {
+  let mut interval = tokio::time::interval(recompute_interval);
+  loop {
+    interval.tick().await;
+
+    // one query for every scanner, so card reads only wait on the db once
+    let usages = card_read_service::count_all_by_scanner_id(
+      &mut *db.lock().await,
+      utils::current_time_millis() - THIRTY_DAYS,
+    )
+    .await;
+
+    let usages = match usages {
+      Ok(usages) => usages,
+      Err(e) => {
+        report_postgres_err(e);
+        continue;
+      }
+    };
+
+    for usage in usages {
+      if let Err(e) = persist_scanner_usage(&mut *db.lock().await, usage).await {
+        report_postgres_err(e);
+      }
+    }
+  }
+}
+
+async fn persist_scanner_usage(
+  con: &mut tokio_postgres::Client,
+  usage: ScannerUsage,
+) -> Result<(), tokio_postgres::Error> {
+  // the ping average is carried over from the last time it was measured
+  let previous = scanner_cache_service::get_by_scanner_id(con, &usage.scanner_id).await?;
+
+  let unchanged = previous.as_ref().map_or(false, |x| {
+    x.lifetime_uses_count == usage.lifetime_uses_count && x.month_uses_count == usage.month_uses_count
+  });
+
+  if unchanged {
+    return Ok(());
+  }
+
+  scanner_cache_service::add(
+    con,
+    usage.creator_user_id,
+    usage.scanner_id,
+    previous.map_or(0, |x| x.average_ping_ms),
+    usage.lifetime_uses_count,
+    usage.month_uses_count,
+  )
+  .await?;
+
+  Ok(())
+}
+
+// telemetry is kept as it was sent for a day, then hourly for a month, then daily
+pub async fn downsample_telemetry(db: Db) {
+  let mut interval = tokio::time::interval(TELEMETRY_DOWNSAMPLE_INTERVAL);
+  loop {
+    interval.tick().await;
+
+    if let Err(e) = downsample_telemetry_once(&mut *db.lock().await).await {
+      report_postgres_err(e);
+    }
+  }
+}
+
+async fn downsample_telemetry_once(
+  con: &mut tokio_postgres::Client,
+) -> Result<(), tokio_postgres::Error> {
+  let now = utils::current_time_millis();
+
+  let mut sp = con.transaction().await?;
+
+  telemetry_service::downsample(&mut sp, ONE_HOUR, now - ONE_DAY).await?;
+  telemetry_service::downsample(&mut sp, ONE_DAY, now - THIRTY_DAYS).await?;
+
+  sp.commit().await?;
+
+  Ok(())
+}
@@ -1,5 +1,6 @@
 #![feature(async_closure)]
 use clap::Clap;
+use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -11,15 +12,48 @@ use mail_service_api::client::MailService;
 mod utils;
 
 mod api;
+mod command_dispatcher;
+mod command_policy;
 mod db_types;
+mod device_protocol;
+mod diagnostics_upload;
+mod feed_protocol;
+mod feed_socket;
+mod firmware_transfer;
 mod handlers;
+mod jobs;
+mod rollout_manager;
+mod scanner_socket;
 
 // database interface
 mod api_key_service;
+mod auth_card_data_service;
+mod auth_card_service;
+mod card_read_service;
+mod command_ack_service;
+mod command_delivery_service;
+mod command_service;
+mod diagnostics_part_service;
+mod diagnostics_service;
 mod email_service;
+mod factory_key_service;
+mod firmware_service;
+mod firmware_update_service;
 mod parent_permission_service;
 mod password_reset_service;
 mod password_service;
+mod rollout_data_service;
+mod rollout_service;
+mod scanner_cache_service;
+mod scanner_config_ack_service;
+mod scanner_config_service;
+mod scanner_data_service;
+mod scanner_key_revocation_service;
+mod scanner_key_service;
+mod scanner_log_service;
+mod scanner_presence_service;
+mod scanner_service;
+mod telemetry_service;
 mod user_data_service;
 mod user_service;
 mod verification_challenge_service;
@@ -36,25 +70,54 @@ struct Opts {
   database_url: String,
   #[clap(short, long)]
   mail_service_url: String,
+  /// json file overriding the default retry, timeout and priority of each command kind
+  #[clap(long)]
+  command_policy_file: Option<String>,
+  /// how many seconds between recomputing the scanner usage counters
+  #[clap(long, default_value = "3600")]
+  usage_recompute_interval: u64,
+  /// how many milliseconds a feed listener has to decide what sound a card read makes
+  #[clap(long, default_value = "2000")]
+  card_read_timeout: u64,
+  /// let scanners without a factory key register and start up, trusting them by uid alone
+  #[clap(long)]
+  allow_legacy_scanners: bool,
 }
 
 pub type Db = Arc<Mutex<Client>>;
 
+// scanners that are currently connected, keyed by scanner_id
+pub type Connections = Arc<Mutex<HashMap<String, scanner_socket::Connection>>>;
+
+// microservices listening for card reads
+pub type Feed = Arc<Mutex<feed_socket::FeedState>>;
+
 #[derive(Clone)]
 pub struct Config {
   pub site_external_url: String,
+  pub command_policies: command_policy::CommandPolicies,
+  pub allow_legacy_scanners: bool,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), tokio_postgres::Error> {
+async fn main() -> Result<(), Box<dyn Error>> {
   let Opts {
     port,
     database_url,
     mail_service_url,
     site_external_url,
+    command_policy_file,
+    usage_recompute_interval,
+    card_read_timeout,
+    allow_legacy_scanners,
   } = Opts::parse();
 
-  let (client, connection) = loop {
+  let command_policies = match command_policy_file {
+    Some(path) => command_policy::CommandPolicies::parse(&std::fs::read_to_string(path)?)?,
+    None => command_policy::CommandPolicies::default(),
+  };
+
+  let (mut client, connection) = loop {
     match tokio_postgres::connect(&database_url, NoTls).await {
       Ok(v) => break v,
       Err(e) => utils::log(utils::Event {
@@ -76,12 +139,42 @@ async fn main() -> Result<(), tokio_postgres::Error> {
     }
   });
 
+  // scanners that were connected when we last shut down have lost their sessions
+  scanner_presence_service::disconnect_all(&mut client).await?;
+
   let db: Db = Arc::new(Mutex::new(client));
 
+  let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+
+  let feed: Feed = Arc::new(Mutex::new(feed_socket::FeedState::new(
+    std::time::Duration::from_millis(card_read_timeout),
+  )));
+
+  // keep the scanner cache up to date
+  tokio::spawn(jobs::persist_ping(db.clone(), connections.clone()));
+  tokio::spawn(jobs::recompute_usage(
+    db.clone(),
+    std::time::Duration::from_secs(usage_recompute_interval),
+  ));
+  tokio::spawn(jobs::downsample_telemetry(db.clone()));
+
+  let config = Config {
+    site_external_url,
+    command_policies,
+    allow_legacy_scanners,
+  };
+
+  // flash firmware rollouts onto their scanners
+  tokio::spawn(rollout_manager::run(
+    config.clone(),
+    db.clone(),
+    connections.clone(),
+  ));
+
   // open connection to mail service
   let mail_service = MailService::new(&mail_service_url).await;
 
-  let api = api::api(Config { site_external_url }, db, mail_service);
+  let api = api::api(config, db, mail_service, connections, feed);
 
   let log = warp::log::custom(|info| {
     // Use a log macro, or slog, or println, or whatever!
@@ -0,0 +1,94 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use cnc_service_api::request::RolloutStatusKind;
+use std::convert::TryInto;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for RolloutData {
+  // select * from rollout_data order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> RolloutData {
+    RolloutData {
+      rollout_data_id: row.get("rollout_data_id"),
+      creation_time: row.get("creation_time"),
+      rollout_id: row.get("rollout_id"),
+      stage: row.get("stage"),
+      // means that there's a mismatch between the values of the enum and the value stored in the column
+      rollout_status_kind: (row.get::<&str, i64>("rollout_status_kind") as u8)
+        .try_into()
+        .unwrap(),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  rollout_id: i64,
+  stage: i64,
+  rollout_status_kind: RolloutStatusKind,
+) -> Result<RolloutData, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let rollout_data_id = con
+    .query_one(
+      "INSERT INTO
+       rollout_data_t(
+        creation_time,
+        rollout_id,
+        stage,
+        rollout_status_kind
+       )
+       VALUES($1, $2, $3, $4)
+       RETURNING rollout_data_id
+      ",
+      &[
+        &creation_time,
+        &rollout_id,
+        &stage,
+        &(rollout_status_kind.clone() as i64),
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return rollout data
+  Ok(RolloutData {
+    rollout_data_id,
+    creation_time,
+    rollout_id,
+    stage,
+    rollout_status_kind,
+  })
+}
+
+// gets most recent rollout data by rollout_id
+pub async fn get_by_rollout_id(
+  con: &mut impl GenericClient,
+  rollout_id: i64,
+) -> Result<Option<RolloutData>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM recent_rollout_data_v WHERE rollout_id=$1",
+      &[&rollout_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// the most recent data of every rollout in the given state
+pub async fn get_all_by_rollout_status_kind(
+  con: &mut impl GenericClient,
+  rollout_status_kind: RolloutStatusKind,
+) -> Result<Vec<RolloutData>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT * FROM recent_rollout_data_v WHERE rollout_status_kind=$1 ORDER BY rollout_id",
+      &[&(rollout_status_kind as i64)],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,362 @@
+use std::error::Error;
+use std::time::Duration;
+
+use super::command_delivery_service;
+use super::command_dispatcher;
+use super::command_service;
+use super::db_types::{Firmware, Rollout, RolloutData};
+use super::device_protocol;
+use super::firmware_service;
+use super::firmware_update_service;
+use super::rollout_data_service;
+use super::rollout_service;
+use super::scanner_presence_service;
+use super::scanner_service;
+use super::utils;
+use super::Config;
+use super::Connections;
+use super::Db;
+
+use cnc_service_api::request::{CommandKind, FirmwareUpdateKind, RolloutStatusKind};
+
+// Rollouts flash their firmware onto a growing share of the scanners, one stage at a time.
+// A rollout may be limited to the scanners at one location, which is how scanners are grouped.
+// A stage is done once each of its scanners has either restarted into the new firmware or failed.
+// The next stage then starts, unless too many have failed, in which case the rollout halts.
+// Scanners that failed before the rollout last started running again, at a new stage or on resume, are flashed again.
+
+// how often running rollouts are checked on
+static ROLLOUT_INTERVAL: Duration = Duration::from_secs(60);
+
+// how long a rollout's FLASH commands wait on scanners that are offline
+static FLASH_DURATION: i64 = 7 * 24 * 60 * 60 * 1000;
+
+enum Outcome {
+  InProgress,
+  Succeeded,
+  Failed,
+  // the scanner was offline for as long as the FLASH command lasted, so it never tried
+  Unreachable,
+}
+
+fn report_postgres_err(e: tokio_postgres::Error) {
+  utils::log(utils::Event {
+    msg: e.to_string(),
+    source: e.source().map(|e| e.to_string()),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+pub async fn run(config: Config, db: Db, connections: Connections) {
+  let mut interval = tokio::time::interval(ROLLOUT_INTERVAL);
+  loop {
+    interval.tick().await;
+
+    let running = match rollout_data_service::get_all_by_rollout_status_kind(
+      &mut *db.lock().await,
+      RolloutStatusKind::Running,
+    )
+    .await
+    {
+      Ok(running) => running,
+      Err(e) => {
+        report_postgres_err(e);
+        continue;
+      }
+    };
+
+    // lock per rollout, so scanners and handlers get a turn in between
+    for rollout_data in running {
+      if let Err(e) = advance(&config, &mut *db.lock().await, &connections, rollout_data).await {
+        report_postgres_err(e);
+      }
+    }
+  }
+}
+
+// flashes the scanners of the current stage, then moves the rollout on if the stage is done
+async fn advance(
+  config: &Config,
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  rollout_data: RolloutData,
+) -> Result<(), tokio_postgres::Error> {
+  let rollout = match rollout_service::get_by_rollout_id(con, rollout_data.rollout_id).await? {
+    Some(rollout) => rollout,
+    None => return Ok(()),
+  };
+
+  let firmware = match firmware_service::get_by_firmware_id(con, rollout.firmware_id).await? {
+    Some(firmware) => firmware,
+    None => return Ok(()),
+  };
+
+  // the same scanners come first every time, so each stage includes the last
+  let scanners = scanner_service::get_active_by_creator_user_id(
+    con,
+    rollout.creator_user_id,
+    rollout.location_id,
+    rollout.rollout_id,
+  )
+  .await?;
+
+  let target_count = target_count(
+    scanners.len() as i64,
+    &rollout.stage_percentages,
+    rollout_data.stage,
+  );
+
+  let mut failed_count = 0;
+  let mut in_progress_count = 0;
+
+  for scanner in scanners.iter().take(target_count as usize) {
+    // scanners too old to FLASH would never be sent the command,
+    // so they're skipped like unreachable ones
+    if !can_flash(con, connections, &scanner.scanner_id).await? {
+      continue;
+    }
+
+    match outcome(con, connections, &firmware, &rollout_data, &scanner.scanner_id).await? {
+      Some(Outcome::Succeeded) => (),
+      Some(Outcome::Unreachable) => (),
+      Some(Outcome::Failed) => failed_count += 1,
+      Some(Outcome::InProgress) => in_progress_count += 1,
+      None => {
+        flash(config, con, connections, &rollout, &scanner.scanner_id).await?;
+        in_progress_count += 1;
+      }
+    }
+  }
+
+  let next = next_status(
+    &rollout,
+    rollout_data.stage,
+    target_count,
+    failed_count,
+    in_progress_count,
+  );
+
+  if let Some((stage, rollout_status_kind)) = next {
+    rollout_data_service::add(con, rollout.rollout_id, stage, rollout_status_kind).await?;
+  }
+
+  Ok(())
+}
+
+// The status changes a rollout's creator may make by hand: pausing, resuming and cancelling.
+// Completed and cancelled rollouts are done for good, and only the manager completes a rollout.
+pub fn manual_transition_allowed(from: &RolloutStatusKind, to: &RolloutStatusKind) -> bool {
+  matches!(
+    (from, to),
+    (RolloutStatusKind::Running, RolloutStatusKind::Halted)
+      | (RolloutStatusKind::Halted, RolloutStatusKind::Running)
+      | (RolloutStatusKind::Running, RolloutStatusKind::Cancelled)
+      | (RolloutStatusKind::Halted, RolloutStatusKind::Cancelled)
+  )
+}
+
+// how many scanners the stage flashes, rounded up so that every stage flashes at least one
+// stages past the last one flash every scanner
+fn target_count(scanner_count: i64, stage_percentages: &[i64], stage: i64) -> i64 {
+  let percentage = stage_percentages.get(stage as usize).copied().unwrap_or(100);
+  (scanner_count * percentage + 99) / 100
+}
+
+// where the rollout goes once this pass over the stage's scanners is done, None if it stays put
+fn next_status(
+  rollout: &Rollout,
+  stage: i64,
+  target_count: i64,
+  failed_count: i64,
+  in_progress_count: i64,
+) -> Option<(i64, RolloutStatusKind)> {
+  if failed_count * 100 > target_count * rollout.failure_threshold_percent {
+    Some((stage, RolloutStatusKind::Halted))
+  } else if in_progress_count > 0 {
+    None
+  } else if stage + 1 < rollout.stage_percentages.len() as i64 {
+    Some((stage + 1, RolloutStatusKind::Running))
+  } else {
+    Some((stage, RolloutStatusKind::Completed))
+  }
+}
+
+// Judged by the scanner's session, or by its last STARTUP if it's offline.
+// Scanners that never started up might run anything, so they're given the benefit of the doubt.
+async fn can_flash(
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  scanner_id: &str,
+) -> Result<bool, tokio_postgres::Error> {
+  let connected = connections.lock().await.get(scanner_id).map(|x| x.protocol_version);
+
+  let protocol_version = match connected {
+    Some(protocol_version) => Some(protocol_version),
+    None => scanner_presence_service::get_protocol_version_by_scanner_id(con, scanner_id).await?,
+  };
+
+  Ok(protocol_version.map_or(true, |x| {
+    device_protocol::command_allowed(&CommandKind::Flash, x)
+  }))
+}
+
+// returns None if the scanner hasn't been told to flash the firmware since the rollout last started running
+async fn outcome(
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  firmware: &Firmware,
+  rollout_data: &RolloutData,
+  scanner_id: &str,
+) -> Result<Option<Outcome>, tokio_postgres::Error> {
+  // it may already run the firmware, no matter how it got there
+  let running_firmware = connections
+    .lock()
+    .await
+    .get(scanner_id)
+    .map_or(false, |x| {
+      x.firmware_version.as_deref() == Some(firmware.firmware_version.as_str())
+    });
+
+  if running_firmware {
+    return Ok(Some(Outcome::Succeeded));
+  }
+
+  let command = match command_service::get_latest_by_scanner_id_and_firmware_id(
+    con,
+    scanner_id,
+    firmware.firmware_id,
+  )
+  .await?
+  {
+    Some(command) => command,
+    None => return Ok(None),
+  };
+
+  let firmware_update_kind = firmware_update_service::get_by_command_id(con, command.command_id)
+    .await?
+    .map(|x| x.firmware_update_kind);
+
+  let expired = utils::current_time_millis() > command.creation_time + command.duration;
+
+  let never_delivered = match (&firmware_update_kind, expired) {
+    (None, true) => command_delivery_service::count_by_command_id(con, command.command_id).await? == 0,
+    _ => false,
+  };
+
+  let outcome = match (firmware_update_kind, expired) {
+    (Some(FirmwareUpdateKind::Succeeded), _) => Outcome::Succeeded,
+    (Some(FirmwareUpdateKind::Failed), _) => Outcome::Failed,
+    (Some(FirmwareUpdateKind::Corrupted), _) => Outcome::Failed,
+    (_, true) if never_delivered => Outcome::Unreachable,
+    (_, true) => Outcome::Failed,
+    (_, false) => Outcome::InProgress,
+  };
+
+  // an old failure is tried again rather than counted against the current stage
+  match outcome {
+    Outcome::Failed if command.creation_time < rollout_data.creation_time => Ok(None),
+    outcome => Ok(Some(outcome)),
+  }
+}
+
+async fn flash(
+  config: &Config,
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  rollout: &Rollout,
+  scanner_id: &str,
+) -> Result<(), tokio_postgres::Error> {
+  let policy = config.command_policies.get(&CommandKind::Flash);
+
+  command_service::add(
+    con,
+    rollout.creator_user_id,
+    scanner_id.to_owned(),
+    CommandKind::Flash,
+    FLASH_DURATION,
+    policy.priority,
+    policy.max_attempts,
+    Some(rollout.firmware_id),
+  )
+  .await?;
+
+  command_dispatcher::wake(connections, scanner_id).await;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rollout(stage_percentages: Vec<i64>, failure_threshold_percent: i64) -> Rollout {
+    Rollout {
+      rollout_id: 1,
+      creation_time: 0,
+      creator_user_id: 1,
+      firmware_id: 1,
+      location_id: None,
+      stage_percentages,
+      failure_threshold_percent,
+    }
+  }
+
+  #[test]
+  fn rollouts_can_be_paused_resumed_and_cancelled() {
+    assert!(manual_transition_allowed(&RolloutStatusKind::Running, &RolloutStatusKind::Halted));
+    assert!(manual_transition_allowed(&RolloutStatusKind::Halted, &RolloutStatusKind::Running));
+    assert!(manual_transition_allowed(&RolloutStatusKind::Running, &RolloutStatusKind::Cancelled));
+    assert!(manual_transition_allowed(&RolloutStatusKind::Halted, &RolloutStatusKind::Cancelled));
+  }
+
+  #[test]
+  fn finished_rollouts_cant_be_changed_by_hand() {
+    assert!(!manual_transition_allowed(&RolloutStatusKind::Running, &RolloutStatusKind::Completed));
+    assert!(!manual_transition_allowed(&RolloutStatusKind::Completed, &RolloutStatusKind::Running));
+    assert!(!manual_transition_allowed(&RolloutStatusKind::Cancelled, &RolloutStatusKind::Running));
+    assert!(!manual_transition_allowed(&RolloutStatusKind::Running, &RolloutStatusKind::Running));
+  }
+
+  #[test]
+  fn target_count_rounds_up() {
+    assert_eq!(target_count(10, &[5, 25, 100], 0), 1);
+    assert_eq!(target_count(10, &[5, 25, 100], 1), 3);
+    assert_eq!(target_count(10, &[5, 25, 100], 2), 10);
+    assert_eq!(target_count(200, &[5, 25, 100], 0), 10);
+  }
+
+  #[test]
+  fn target_count_past_the_last_stage_is_everything() {
+    assert_eq!(target_count(10, &[5], 3), 10);
+    assert_eq!(target_count(0, &[5], 0), 0);
+  }
+
+  #[test]
+  fn stays_put_while_scanners_are_in_progress() {
+    assert!(next_status(&rollout(vec![5, 25, 100], 10), 0, 10, 1, 3).is_none());
+  }
+
+  #[test]
+  fn halts_past_the_threshold() {
+    assert!(matches!(
+      next_status(&rollout(vec![5, 25, 100], 10), 1, 10, 2, 3),
+      Some((1, RolloutStatusKind::Halted))
+    ));
+  }
+
+  #[test]
+  fn failures_at_the_threshold_dont_halt() {
+    assert!(matches!(
+      next_status(&rollout(vec![5, 25, 100], 10), 0, 10, 1, 0),
+      Some((1, RolloutStatusKind::Running))
+    ));
+  }
+
+  #[test]
+  fn completes_after_the_last_stage() {
+    assert!(matches!(
+      next_status(&rollout(vec![5, 25, 100], 10), 2, 10, 0, 0),
+      Some((2, RolloutStatusKind::Completed))
+    ));
+  }
+}
@@ -0,0 +1,115 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for Rollout {
+  // select * from rollout order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> Rollout {
+    Rollout {
+      rollout_id: row.get("rollout_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      firmware_id: row.get("firmware_id"),
+      location_id: row.get("location_id"),
+      stage_percentages: row.get("stage_percentages"),
+      failure_threshold_percent: row.get("failure_threshold_percent"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  firmware_id: i64,
+  location_id: Option<i64>,
+  stage_percentages: Vec<i64>,
+  failure_threshold_percent: i64,
+) -> Result<Rollout, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let rollout_id = con
+    .query_one(
+      "INSERT INTO
+       rollout_t(
+        creation_time,
+        creator_user_id,
+        firmware_id,
+        location_id,
+        stage_percentages,
+        failure_threshold_percent
+       )
+       VALUES($1, $2, $3, $4, $5, $6)
+       RETURNING rollout_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &firmware_id,
+        &location_id,
+        &stage_percentages,
+        &failure_threshold_percent,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return rollout
+  Ok(Rollout {
+    rollout_id,
+    creation_time,
+    creator_user_id,
+    firmware_id,
+    location_id,
+    stage_percentages,
+    failure_threshold_percent,
+  })
+}
+
+pub async fn get_by_rollout_id(
+  con: &mut impl GenericClient,
+  rollout_id: i64,
+) -> Result<Option<Rollout>, tokio_postgres::Error> {
+  let result = con
+    .query_opt("SELECT * FROM rollout_t WHERE rollout_id=$1", &[&rollout_id])
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// filters on the rollout's most recent rollout data, and only returns rollouts created by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::RolloutViewProps,
+) -> Result<Vec<Rollout>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT r.* FROM rollout_t r
+       JOIN recent_rollout_data_v rd ON rd.rollout_id = r.rollout_id
+       WHERE 1 = 1
+       AND ($1::bigint[] IS NULL OR r.rollout_id = ANY($1))
+       AND ($2::bigint   IS NULL OR r.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR r.creation_time <= $3)
+       AND ($4::bigint[] IS NULL OR r.creator_user_id = ANY($4))
+       AND ($5::bigint[] IS NULL OR r.firmware_id = ANY($5))
+       AND ($6::bigint   IS NULL OR rd.rollout_status_kind = $6)
+       AND r.creator_user_id = $7
+       ORDER BY r.rollout_id
+      ",
+      &[
+        &props.rollout_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.firmware_id,
+        &props.rollout_status_kind.map(|x| x as i64),
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,133 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerCache {
+  // select * from scanner_cache order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerCache {
+    ScannerCache {
+      scanner_cache_id: row.get("scanner_cache_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      scanner_id: row.get("scanner_id"),
+      average_ping_ms: row.get("average_ping_ms"),
+      lifetime_uses_count: row.get("lifetime_uses_count"),
+      month_uses_count: row.get("month_uses_count"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  scanner_id: String,
+  average_ping_ms: i64,
+  lifetime_uses_count: i64,
+  month_uses_count: i64,
+) -> Result<ScannerCache, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_cache_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_cache_t(
+        creation_time,
+        creator_user_id,
+        scanner_id,
+        average_ping_ms,
+        lifetime_uses_count,
+        month_uses_count
+       )
+       VALUES($1, $2, $3, $4, $5, $6)
+       RETURNING scanner_cache_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &scanner_id,
+        &average_ping_ms,
+        &lifetime_uses_count,
+        &month_uses_count,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return scanner cache
+  Ok(ScannerCache {
+    scanner_cache_id,
+    creation_time,
+    creator_user_id,
+    scanner_id,
+    average_ping_ms,
+    lifetime_uses_count,
+    month_uses_count,
+  })
+}
+
+// gets most recent scanner cache by scanner_id
+pub async fn get_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<Option<ScannerCache>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM recent_scanner_cache_v WHERE scanner_id=$1",
+      &[&scanner_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// deletes caches that have been superseded, keeping the most recent of each scanner
+pub async fn prune(
+  con: &mut impl GenericClient,
+  max_creation_time: i64,
+) -> Result<u64, tokio_postgres::Error> {
+  let deleted = con
+    .execute(
+      "DELETE FROM scanner_cache_t
+       WHERE creation_time < $1
+       AND scanner_cache_id NOT IN (SELECT scanner_cache_id FROM recent_scanner_cache_v)
+      ",
+      &[&max_creation_time],
+    )
+    .await?;
+
+  Ok(deleted)
+}
+
+// only looks at the most recent cache of each scanner, and only at scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::ScannerStatsViewProps,
+) -> Result<Vec<ScannerCache>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT sc.* FROM recent_scanner_cache_v sc
+       INNER JOIN scanner_t s ON s.scanner_id = sc.scanner_id
+       WHERE 1 = 1
+       AND ($1::text[]   IS NULL OR sc.scanner_id = ANY($1))
+       AND ($2::bigint[] IS NULL OR sc.creator_user_id = ANY($2))
+       AND ($3::bigint   IS NULL OR sc.average_ping_ms >= $3)
+       AND ($4::bigint   IS NULL OR sc.average_ping_ms <= $4)
+       AND s.creator_user_id = $5
+       ORDER BY sc.scanner_id
+      ",
+      &[
+        &props.scanner_id,
+        &props.creator_user_id,
+        &props.min_average_ping_ms,
+        &props.max_average_ping_ms,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,62 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerConfigAck {
+  // select * from scanner_config_ack order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerConfigAck {
+    ScannerConfigAck {
+      scanner_config_ack_id: row.get("scanner_config_ack_id"),
+      creation_time: row.get("creation_time"),
+      scanner_config_id: row.get("scanner_config_id"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_config_id: i64,
+) -> Result<ScannerConfigAck, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_config_ack_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_config_ack_t(
+        creation_time,
+        scanner_config_id
+       )
+       VALUES($1, $2)
+       RETURNING scanner_config_ack_id
+      ",
+      &[&creation_time, &scanner_config_id],
+    )
+    .await?
+    .get(0);
+
+  // return scanner config ack
+  Ok(ScannerConfigAck {
+    scanner_config_ack_id,
+    creation_time,
+    scanner_config_id,
+  })
+}
+
+// the first time the scanner applied this config
+pub async fn get_by_scanner_config_id(
+  con: &mut impl GenericClient,
+  scanner_config_id: i64,
+) -> Result<Option<ScannerConfigAck>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM scanner_config_ack_t WHERE scanner_config_id=$1
+       ORDER BY scanner_config_ack_id
+       LIMIT 1
+      ",
+      &[&scanner_config_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
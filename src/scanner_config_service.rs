@@ -0,0 +1,142 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerConfig {
+  // select * from scanner_config order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerConfig {
+    ScannerConfig {
+      scanner_config_id: row.get("scanner_config_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      scanner_id: row.get("scanner_id"),
+      beep_volume: row.get("beep_volume"),
+      led_brightness: row.get("led_brightness"),
+      read_debounce: row.get("read_debounce"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  scanner_id: String,
+  beep_volume: i64,
+  led_brightness: i64,
+  read_debounce: i64,
+) -> Result<ScannerConfig, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_config_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_config_t(
+        creation_time,
+        creator_user_id,
+        scanner_id,
+        beep_volume,
+        led_brightness,
+        read_debounce
+       )
+       VALUES($1, $2, $3, $4, $5, $6)
+       RETURNING scanner_config_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &scanner_id,
+        &beep_volume,
+        &led_brightness,
+        &read_debounce,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return scanner config
+  Ok(ScannerConfig {
+    scanner_config_id,
+    creation_time,
+    creator_user_id,
+    scanner_id,
+    beep_volume,
+    led_brightness,
+    read_debounce,
+  })
+}
+
+pub async fn get_by_scanner_config_id(
+  con: &mut impl GenericClient,
+  scanner_config_id: i64,
+) -> Result<Option<ScannerConfig>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM scanner_config_t WHERE scanner_config_id=$1",
+      &[&scanner_config_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// gets most recent scanner config by scanner_id
+pub async fn get_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<Option<ScannerConfig>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM recent_scanner_config_v WHERE scanner_id=$1",
+      &[&scanner_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// only returns the configs of scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::ScannerConfigViewProps,
+) -> Result<Vec<ScannerConfig>, tokio_postgres::Error> {
+  let sql = [
+    if props.only_recent {
+      "SELECT sc.* FROM recent_scanner_config_v sc"
+    } else {
+      "SELECT sc.* FROM scanner_config_t sc"
+    },
+    " INNER JOIN scanner_t s ON s.scanner_id = sc.scanner_id",
+    " WHERE 1 = 1",
+    " AND ($1::bigint[] IS NULL OR sc.scanner_config_id = ANY($1))",
+    " AND ($2::bigint   IS NULL OR sc.creation_time >= $2)",
+    " AND ($3::bigint   IS NULL OR sc.creation_time <= $3)",
+    " AND ($4::bigint[] IS NULL OR sc.creator_user_id = ANY($4))",
+    " AND ($5::text[]   IS NULL OR sc.scanner_id = ANY($5))",
+    " AND s.creator_user_id = $6",
+    " ORDER BY sc.scanner_config_id",
+  ]
+  .join("\n");
+
+  let stmnt = con.prepare(&sql).await?;
+
+  let results = con
+    .query(
+      &stmnt,
+      &[
+        &props.scanner_config_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.scanner_id,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
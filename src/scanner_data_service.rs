@@ -0,0 +1,82 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerData {
+  // select * from scanner_data order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerData {
+    ScannerData {
+      scanner_data_id: row.get("scanner_data_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      scanner_id: row.get("scanner_id"),
+      location_id: row.get("location_id"),
+      description: row.get("description"),
+      active: row.get("active"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  scanner_id: String,
+  location_id: Option<i64>,
+  description: String,
+  active: bool,
+) -> Result<ScannerData, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_data_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_data_t(
+        creation_time,
+        creator_user_id,
+        scanner_id,
+        location_id,
+        description,
+        active
+       )
+       VALUES($1, $2, $3, $4, $5, $6)
+       RETURNING scanner_data_id
+      ",
+      &[
+        &creation_time,
+        &creator_user_id,
+        &scanner_id,
+        &location_id,
+        &description,
+        &active,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return scanner data
+  Ok(ScannerData {
+    scanner_data_id,
+    creation_time,
+    creator_user_id,
+    scanner_id,
+    location_id,
+    description,
+    active,
+  })
+}
+
+// gets most recent scanner data by scanner_id
+pub async fn get_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<Option<ScannerData>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM recent_scanner_data_v WHERE scanner_id=$1",
+      &[&scanner_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
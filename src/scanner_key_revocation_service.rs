@@ -0,0 +1,55 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerKeyRevocation {
+  // select * from scanner_key_revocation order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerKeyRevocation {
+    ScannerKeyRevocation {
+      scanner_key_revocation_id: row.get("scanner_key_revocation_id"),
+      creation_time: row.get("creation_time"),
+      scanner_key_id: row.get("scanner_key_id"),
+    }
+  }
+}
+
+// revokes every key of the scanner except the given one
+pub async fn revoke_all_except(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  scanner_key_id: i64,
+) -> Result<u64, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  con
+    .execute(
+      "INSERT INTO
+       scanner_key_revocation_t(
+        creation_time,
+        scanner_key_id
+       )
+       SELECT $1, sk.scanner_key_id
+       FROM scanner_key_t sk
+       WHERE sk.scanner_id = $2
+       AND sk.scanner_key_id != $3
+       AND sk.scanner_key_id NOT IN (SELECT scanner_key_id FROM scanner_key_revocation_t)
+      ",
+      &[&creation_time, &scanner_id, &scanner_key_id],
+    )
+    .await
+}
+
+pub async fn get_by_scanner_key_id(
+  con: &mut impl GenericClient,
+  scanner_key_id: i64,
+) -> Result<Option<ScannerKeyRevocation>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM scanner_key_revocation_t WHERE scanner_key_id=$1",
+      &[&scanner_key_id],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
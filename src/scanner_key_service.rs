@@ -0,0 +1,161 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerKey {
+  // select * from scanner_key order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerKey {
+    ScannerKey {
+      scanner_key_id: row.get("scanner_key_id"),
+      creation_time: row.get("creation_time"),
+      scanner_id: row.get("scanner_id"),
+      scanner_key_hash: row.get("scanner_key_hash"),
+      duration: row.get("duration"),
+      last_used_time: row.get("last_used_time"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_id: String,
+  scanner_key_hash: String,
+  duration: i64,
+) -> Result<ScannerKey, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_key_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_key_t(
+        creation_time,
+        scanner_id,
+        scanner_key_hash,
+        duration,
+        last_used_time
+       )
+       VALUES($1, $2, $3, $4, $5)
+       RETURNING scanner_key_id
+      ",
+      &[
+        &creation_time,
+        &scanner_id,
+        &scanner_key_hash,
+        &duration,
+        &creation_time,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return scanner key
+  Ok(ScannerKey {
+    scanner_key_id,
+    creation_time,
+    scanner_id,
+    scanner_key_hash,
+    duration,
+    last_used_time: creation_time,
+  })
+}
+
+pub async fn get_by_scanner_key_hash(
+  con: &mut impl GenericClient,
+  scanner_key_hash: &str,
+) -> Result<Option<ScannerKey>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT * FROM scanner_key_t WHERE scanner_key_hash=$1",
+      &[&scanner_key_hash],
+    )
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// whether the scanner holds a key that is neither expired nor revoked
+pub async fn exists_live_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<bool, tokio_postgres::Error> {
+  let count: i64 = con
+    .query_one(
+      "SELECT count(*) FROM scanner_key_t sk
+       WHERE sk.scanner_id = $1
+       AND sk.creation_time + sk.duration >= $2
+       AND sk.scanner_key_id NOT IN (SELECT scanner_key_id FROM scanner_key_revocation_t)
+      ",
+      &[&scanner_id, &current_time_millis()],
+    )
+    .await?
+    .get(0);
+  Ok(count > 0)
+}
+
+// records that the scanner presented this key
+pub async fn touch(
+  con: &mut impl GenericClient,
+  scanner_key_id: i64,
+) -> Result<i64, tokio_postgres::Error> {
+  let last_used_time = current_time_millis();
+
+  con
+    .execute(
+      "UPDATE scanner_key_t SET last_used_time=$1 WHERE scanner_key_id=$2",
+      &[&last_used_time, &scanner_key_id],
+    )
+    .await?;
+
+  Ok(last_used_time)
+}
+
+// records that the scanners holding these keys are still connected
+pub async fn touch_all(
+  con: &mut impl GenericClient,
+  scanner_key_ids: &[i64],
+) -> Result<u64, tokio_postgres::Error> {
+  con
+    .execute(
+      "UPDATE scanner_key_t SET last_used_time=$1 WHERE scanner_key_id = ANY($2)",
+      &[&current_time_millis(), &scanner_key_ids],
+    )
+    .await
+}
+
+// only returns the keys of scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::ScannerKeyViewProps,
+) -> Result<Vec<ScannerKey>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT sk.* FROM scanner_key_t sk
+       INNER JOIN scanner_t s ON s.scanner_id = sk.scanner_id
+       WHERE 1 = 1
+       AND ($1::bigint[] IS NULL OR sk.scanner_key_id = ANY($1))
+       AND ($2::bigint   IS NULL OR sk.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR sk.creation_time <= $3)
+       AND ($4::text[]   IS NULL OR sk.scanner_id = ANY($4))
+       AND ($5::bigint   IS NULL OR sk.last_used_time >= $5)
+       AND ($6::bigint   IS NULL OR sk.last_used_time <= $6)
+       AND s.creator_user_id = $7
+       ORDER BY sk.scanner_key_id
+      ",
+      &[
+        &props.scanner_key_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.scanner_id,
+        &props.min_last_used_time,
+        &props.max_last_used_time,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,96 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use super::utils::SeverityKind;
+use std::convert::TryInto;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerLog {
+  // select * from scanner_log order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerLog {
+    ScannerLog {
+      scanner_log_id: row.get("scanner_log_id"),
+      creation_time: row.get("creation_time"),
+      scanner_id: row.get("scanner_id"),
+      // means that there's a mismatch between the values of the enum and the value stored in the column
+      severity_kind: (row.get::<&str, i64>("severity_kind") as u8)
+        .try_into()
+        .unwrap(),
+      msg: row.get("msg"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_id: String,
+  severity_kind: SeverityKind,
+  msg: String,
+) -> Result<ScannerLog, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_log_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_log_t(
+        creation_time,
+        scanner_id,
+        severity_kind,
+        msg
+       )
+       VALUES($1, $2, $3, $4)
+       RETURNING scanner_log_id
+      ",
+      &[
+        &creation_time,
+        &scanner_id,
+        &(severity_kind.clone() as i64),
+        &msg,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return scanner log
+  Ok(ScannerLog {
+    scanner_log_id,
+    creation_time,
+    scanner_id,
+    severity_kind,
+    msg,
+  })
+}
+
+// only returns logs from scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::ScannerLogViewProps,
+) -> Result<Vec<ScannerLog>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT sl.* FROM scanner_log_t sl
+       INNER JOIN scanner_t s ON s.scanner_id = sl.scanner_id
+       WHERE 1 = 1
+       AND ($1::bigint[] IS NULL OR sl.scanner_log_id = ANY($1))
+       AND ($2::bigint   IS NULL OR sl.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR sl.creation_time <= $3)
+       AND ($4::text[]   IS NULL OR sl.scanner_id = ANY($4))
+       AND ($5::bigint   IS NULL OR sl.severity_kind >= $5)
+       AND s.creator_user_id = $6
+       ORDER BY sl.scanner_log_id
+      ",
+      &[
+        &props.scanner_log_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.scanner_id,
+        &props.min_severity_kind.map(|x| x as i64),
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
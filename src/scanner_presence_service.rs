@@ -0,0 +1,190 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for ScannerPresence {
+  // select * from scanner_presence order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> ScannerPresence {
+    ScannerPresence {
+      scanner_presence_id: row.get("scanner_presence_id"),
+      creation_time: row.get("creation_time"),
+      scanner_id: row.get("scanner_id"),
+      connected: row.get("connected"),
+      protocol_version: row.get("protocol_version"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_id: String,
+  connected: bool,
+  protocol_version: Option<i64>,
+) -> Result<ScannerPresence, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let scanner_presence_id = con
+    .query_one(
+      "INSERT INTO
+       scanner_presence_t(
+        creation_time,
+        scanner_id,
+        connected,
+        protocol_version
+       )
+       VALUES($1, $2, $3, $4)
+       RETURNING scanner_presence_id
+      ",
+      &[&creation_time, &scanner_id, &connected, &protocol_version],
+    )
+    .await?
+    .get(0);
+
+  // return scanner presence
+  Ok(ScannerPresence {
+    scanner_presence_id,
+    creation_time,
+    scanner_id,
+    connected,
+    protocol_version,
+  })
+}
+
+// the protocol version the scanner negotiated at its most recent STARTUP, if it's known
+pub async fn get_protocol_version_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<Option<i64>, tokio_postgres::Error> {
+  let result = con
+    .query_opt(
+      "SELECT protocol_version FROM scanner_presence_t
+       WHERE scanner_id=$1 AND connected
+       ORDER BY scanner_presence_id DESC
+       LIMIT 1
+      ",
+      &[&scanner_id],
+    )
+    .await?
+    .and_then(|row| row.get(0));
+
+  Ok(result)
+}
+
+// records a disconnect for every scanner that is still marked as connected
+pub async fn disconnect_all(con: &mut impl GenericClient) -> Result<u64, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  con
+    .execute(
+      "INSERT INTO
+       scanner_presence_t(
+        creation_time,
+        scanner_id,
+        connected
+       )
+       SELECT $1, sp.scanner_id, false
+       FROM recent_scanner_presence_v sp
+       WHERE sp.connected
+      ",
+      &[&creation_time],
+    )
+    .await
+}
+
+// only returns the presence of scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::ScannerPresenceViewProps,
+) -> Result<Vec<ScannerPresence>, tokio_postgres::Error> {
+  let sql = [
+    if props.only_recent {
+      "SELECT sp.* FROM recent_scanner_presence_v sp"
+    } else {
+      "SELECT sp.* FROM scanner_presence_t sp"
+    },
+    " INNER JOIN scanner_t s ON s.scanner_id = sp.scanner_id",
+    " WHERE 1 = 1",
+    " AND ($1::bigint[] IS NULL OR sp.scanner_presence_id = ANY($1))",
+    " AND ($2::bigint   IS NULL OR sp.creation_time >= $2)",
+    " AND ($3::bigint   IS NULL OR sp.creation_time <= $3)",
+    " AND ($4::text[]   IS NULL OR sp.scanner_id = ANY($4))",
+    " AND ($5::bool     IS NULL OR sp.connected = $5)",
+    " AND s.creator_user_id = $6",
+    " ORDER BY sp.scanner_presence_id",
+  ]
+  .join("\n");
+
+  let stmnt = con.prepare(&sql).await?;
+
+  let results = con
+    .query(
+      &stmnt,
+      &[
+        &props.scanner_presence_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.scanner_id,
+        &props.connected,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+// How long each of the user's scanners was connected between min_time and max_time, in milliseconds.
+// A scanner's state at min_time is whatever its last event before then said.
+pub async fn get_uptime(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  scanner_id: Option<Vec<String>>,
+  min_time: i64,
+  max_time: i64,
+) -> Result<Vec<ScannerUptime>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "WITH events AS (
+        SELECT before.scanner_id, $2::bigint creation_time, before.connected
+        FROM (
+          SELECT DISTINCT ON (scanner_id) scanner_id, connected
+          FROM scanner_presence_t
+          WHERE creation_time < $2
+          ORDER BY scanner_id, scanner_presence_id DESC
+        ) before
+        UNION ALL
+        SELECT scanner_id, creation_time, connected
+        FROM scanner_presence_t
+        WHERE creation_time >= $2 AND creation_time < $3
+       ), spans AS (
+        SELECT
+          scanner_id,
+          connected,
+          creation_time,
+          lead(creation_time, 1, $3) OVER (PARTITION BY scanner_id ORDER BY creation_time) end_time
+        FROM events
+       )
+       SELECT
+         sp.scanner_id,
+         COALESCE(sum(sp.end_time - sp.creation_time) FILTER (WHERE sp.connected), 0)::bigint connected_duration
+       FROM spans sp
+       INNER JOIN scanner_t s ON s.scanner_id = sp.scanner_id
+       WHERE s.creator_user_id = $1
+       AND ($4::text[] IS NULL OR sp.scanner_id = ANY($4))
+       GROUP BY sp.scanner_id
+       ORDER BY sp.scanner_id
+      ",
+      &[&creator_user_id, &min_time, &max_time, &scanner_id],
+    )
+    .await?
+    .into_iter()
+    .map(|row| ScannerUptime {
+      scanner_id: row.get("scanner_id"),
+      connected_duration: row.get("connected_duration"),
+    })
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,132 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for Scanner {
+  // select * from scanner order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> Scanner {
+    Scanner {
+      scanner_id: row.get("scanner_id"),
+      creation_time: row.get("creation_time"),
+      creator_user_id: row.get("creator_user_id"),
+      auth_card_id: row.get("auth_card_id"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_id: String,
+  creator_user_id: i64,
+  auth_card_id: String,
+) -> Result<Scanner, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  con
+    .execute(
+      "INSERT INTO
+       scanner_t(
+        scanner_id,
+        creation_time,
+        creator_user_id,
+        auth_card_id
+       )
+       VALUES($1, $2, $3, $4)
+      ",
+      &[&scanner_id, &creation_time, &creator_user_id, &auth_card_id],
+    )
+    .await?;
+
+  // return scanner
+  Ok(Scanner {
+    scanner_id,
+    creation_time,
+    creator_user_id,
+    auth_card_id,
+  })
+}
+
+pub async fn exists_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<bool, tokio_postgres::Error> {
+  let count: i64 = con
+    .query_one(
+      "SELECT count(*) FROM scanner_t WHERE scanner_id=$1",
+      &[&scanner_id],
+    )
+    .await?
+    .get(0);
+  Ok(count != 0)
+}
+
+pub async fn get_by_scanner_id(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+) -> Result<Option<Scanner>, tokio_postgres::Error> {
+  let result = con
+    .query_opt("SELECT * FROM scanner_t WHERE scanner_id=$1", &[&scanner_id])
+    .await?
+    .map(|row| row.into());
+
+  Ok(result)
+}
+
+// filters on the scanner's most recent scanner data
+pub async fn query(
+  con: &mut impl GenericClient,
+  props: cnc_service_api::request::DeviceViewProps,
+) -> Result<Vec<Scanner>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT s.* FROM scanner_t s
+       JOIN recent_scanner_data_v sd ON sd.scanner_id = s.scanner_id
+       WHERE 1 = 1
+       AND ($1::text[]   IS NULL OR s.scanner_id = ANY($1))
+       AND ($2::bigint   IS NULL OR s.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR s.creation_time <= $3)
+       AND ($4::bigint[] IS NULL OR s.creator_user_id = ANY($4))
+       AND ($5::bigint[] IS NULL OR sd.location_id = ANY($5))
+       AND ($6::bool     IS NULL OR sd.active = $6)
+       ORDER BY s.creation_time
+      ",
+      &[
+        &props.device_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.creator_user_id,
+        &props.location_id,
+        &props.active,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+// the active scanners of a user, shuffled the same way every time for the same seed
+pub async fn get_active_by_creator_user_id(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  location_id: Option<i64>,
+  seed: i64,
+) -> Result<Vec<Scanner>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT s.* FROM scanner_t s
+       JOIN recent_scanner_data_v sd ON sd.scanner_id = s.scanner_id
+       WHERE s.creator_user_id = $1
+       AND sd.active
+       AND ($2::bigint IS NULL OR sd.location_id = $2)
+       ORDER BY md5(s.scanner_id || $3::text)
+      ",
+      &[&creator_user_id, &location_id, &seed.to_string()],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
@@ -0,0 +1,1438 @@
+use futures::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio_postgres::GenericClient;
+use warp::ws::{Message, WebSocket};
+
+use cnc_service_api::request::CommandKind;
+
+use super::card_read_service;
+use super::command_ack_service;
+use super::command_dispatcher;
+use super::command_service;
+use super::db_types::{CardRead, ScannerKey};
+use super::device_protocol;
+use super::device_protocol::{
+  CapabilityKind, DeviceMessage, Encoding, OfflineCardRead, ServerMessage, SoundKind,
+};
+use super::diagnostics_upload;
+use super::factory_key_service;
+use super::feed_socket;
+use super::firmware_transfer;
+use super::scanner_config_ack_service;
+use super::scanner_config_service;
+use super::scanner_data_service;
+use super::scanner_key_revocation_service;
+use super::scanner_key_service;
+use super::scanner_log_service;
+use super::scanner_presence_service;
+use super::scanner_service;
+use super::telemetry_service;
+use super::utils;
+use super::Config;
+use super::Connections;
+use super::Db;
+use super::Feed;
+
+// how long a scanner's api key lasts. Keys past half of this are rotated at STARTUP.
+static KEY_DURATION: i64 = 90 * 24 * 60 * 60 * 1000;
+
+// how often we measure the round trip time to the scanner
+static PING_INTERVAL: Duration = Duration::from_secs(15);
+
+// how many round trip times the rolling average is taken over
+static PING_SAMPLES: usize = 20;
+
+// how far ahead of the server a scanner's clock may run before its reads are flagged
+static MAX_CLOCK_AHEAD: i64 = 60 * 1000;
+
+// how long a scanner may plausibly have kept a card read while offline
+static MAX_OFFLINE_AGE: i64 = 7 * 24 * 60 * 60 * 1000;
+
+// how long repeat reads of the same card are ignored for scanners that haven't been configured
+static DEFAULT_READ_DEBOUNCE: i64 = 3000;
+
+// the longest LOG msg that is stored, in characters. Longer ones are cut off.
+static MAX_LOG_LENGTH: usize = 1024;
+
+// how many LOG and TELEMETRY messages a session may send per RATE_LIMIT_WINDOW before the rest are dropped
+static MAX_LOGS_PER_WINDOW: i64 = 60;
+static MAX_TELEMETRY_PER_WINDOW: i64 = 6;
+static RATE_LIMIT_WINDOW: i64 = 60 * 1000;
+
+// used to tell apart two sessions that claimed the same scanner
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+// A live session with a scanner that has completed STARTUP.
+// Other parts of the server reach the scanner through `sender`.
+pub struct Connection {
+  pub connection_id: u64,
+  // the last time we heard anything from the scanner
+  pub last_seen_time: i64,
+  // what was negotiated at STARTUP
+  pub protocol_version: i64,
+  pub firmware_version: Option<String>,
+  // the most recent round trip times in milliseconds
+  pub ping_samples: VecDeque<i64>,
+  pub sender: mpsc::UnboundedSender<ServerMessage>,
+  // commands someone is waiting on the ack of, keyed by command_id
+  pub pending_acks: HashMap<i64, Vec<oneshot::Sender<()>>>,
+  // wakes the command delivery loop
+  pub wakeup: Arc<Notify>,
+  // the key the scanner started up with, and the one sent in ROTATE_KEY that it hasn't acked yet
+  pub scanner_key_id: i64,
+  pub pending_key_id: Option<i64>,
+}
+
+impl Connection {
+  pub fn average_ping_ms(&self) -> Option<i64> {
+    match self.ping_samples.len() as i64 {
+      0 => None,
+      n => Some(self.ping_samples.iter().sum::<i64>() / n),
+    }
+  }
+}
+
+// counts the messages of one kind in the current window
+struct RateLimit {
+  max_per_window: i64,
+  window_start: i64,
+  count: i64,
+}
+
+impl RateLimit {
+  fn new(max_per_window: i64) -> RateLimit {
+    RateLimit {
+      max_per_window,
+      window_start: 0,
+      count: 0,
+    }
+  }
+
+  // whether another message may be sent at this time
+  fn allow(&mut self, now: i64) -> bool {
+    if now >= self.window_start + RATE_LIMIT_WINDOW {
+      self.window_start = now;
+      self.count = 0;
+    }
+    self.count += 1;
+    self.count <= self.max_per_window
+  }
+
+  // the first message over the limit is reported, the rest of the window is dropped quietly
+  fn just_exceeded(&self) -> bool {
+    self.count == self.max_per_window + 1
+  }
+}
+
+// a STARTUP waiting on the scanner to answer CHALLENGE
+struct Challenge {
+  uid: String,
+  api_key: Option<String>,
+  secret_key: String,
+  nonce: String,
+}
+
+struct Session {
+  connection_id: u64,
+  sender: mpsc::UnboundedSender<ServerMessage>,
+  wakeup: Arc<Notify>,
+  protocol_version: i64,
+  firmware_version: Option<String>,
+  read_epoch: Option<i64>,
+  challenge: Option<Challenge>,
+  // the FLASH commands whose firmware has been asked for in this session
+  firmware_transfers: HashSet<i64>,
+  // set once STARTUP succeeds
+  scanner_id: Option<String>,
+  log_rate_limit: RateLimit,
+  telemetry_rate_limit: RateLimit,
+}
+
+fn report_postgres_err(e: tokio_postgres::Error) {
+  utils::log(utils::Event {
+    msg: e.to_string(),
+    source: e.source().map(|e| e.to_string()),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+fn report_protocol_err(msg: String, scanner_id: Option<&str>) {
+  utils::log(utils::Event {
+    msg,
+    source: Some(format!("scanner socket: {:?}", scanner_id)),
+    severity: utils::SeverityKind::Warning,
+  });
+}
+
+// someone may be trying to impersonate a scanner
+fn report_auth_fail(msg: String, uid: &str) {
+  utils::log(utils::Event {
+    msg,
+    source: Some(format!("scanner authentication: {}", uid)),
+    severity: utils::SeverityKind::Error,
+  });
+}
+
+// anyone who knows the uid could be this scanner
+fn report_legacy_startup(uid: &str) {
+  utils::log(utils::Event {
+    msg: "startup without a factory key".to_owned(),
+    source: Some(format!("scanner authentication: {}", uid)),
+    severity: utils::SeverityKind::Warning,
+  });
+}
+
+// the scanner proves it holds its factory secret by keying an HMAC of the nonce with it
+fn verify_challenge(challenge: &Challenge, response: &[u8]) -> bool {
+  match Hmac::<Sha256>::new_from_slice(challenge.secret_key.as_bytes()) {
+    Ok(mut mac) => {
+      mac.update(challenge.nonce.as_bytes());
+      // compares in constant time
+      mac.verify(response).is_ok()
+    }
+    Err(_) => false,
+  }
+}
+
+// returns None for frames that don't carry a message, like pings
+fn decode(msg: &Message) -> Option<Result<(Encoding, DeviceMessage), String>> {
+  if msg.is_text() {
+    Some(
+      serde_json::from_slice(msg.as_bytes())
+        .map(|x| (Encoding::Json, x))
+        .map_err(|e| e.to_string()),
+    )
+  } else if msg.is_binary() {
+    Some(
+      serde_cbor::from_slice(msg.as_bytes())
+        .map(|x| (Encoding::Cbor, x))
+        .map_err(|e| e.to_string()),
+    )
+  } else {
+    None
+  }
+}
+
+fn encode(encoding: Encoding, msg: &ServerMessage) -> Message {
+  match encoding {
+    Encoding::Json => Message::text(serde_json::to_string(msg).unwrap()),
+    Encoding::Cbor => Message::binary(serde_cbor::to_vec(msg).unwrap()),
+  }
+}
+
+// this function runs for as long as the scanner stays connected
+pub async fn handle(
+  ws: WebSocket,
+  config: Config,
+  db: Db,
+  connections: Connections,
+  feed: Feed,
+) {
+  let (mut ws_tx, mut ws_rx) = ws.split();
+
+  let (sender, mut receiver) = mpsc::unbounded_channel::<ServerMessage>();
+
+  // json until the scanner says otherwise at STARTUP
+  let (encoding_tx, encoding_rx) = watch::channel(Encoding::Json);
+
+  // forward everything sent to this session out over the websocket,
+  // pinging every so often with the time the ping was sent as the payload
+  tokio::spawn(async move {
+    let mut ping_interval = tokio::time::interval(PING_INTERVAL);
+    loop {
+      let msg = tokio::select! {
+        msg = receiver.recv() => match msg {
+          Some(msg) => encode(*encoding_rx.borrow(), &msg),
+          None => break,
+        },
+        _ = ping_interval.tick() => {
+          Message::ping(utils::current_time_millis().to_be_bytes().to_vec())
+        }
+      };
+      if let Err(e) = ws_tx.send(msg).await {
+        report_protocol_err(e.to_string(), None);
+        break;
+      }
+    }
+  });
+
+  let mut session = Session {
+    connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+    sender,
+    wakeup: Arc::new(Notify::new()),
+    protocol_version: 1,
+    firmware_version: None,
+    read_epoch: None,
+    challenge: None,
+    firmware_transfers: HashSet::new(),
+    scanner_id: None,
+    log_rate_limit: RateLimit::new(MAX_LOGS_PER_WINDOW),
+    telemetry_rate_limit: RateLimit::new(MAX_TELEMETRY_PER_WINDOW),
+  };
+
+  while let Some(result) = ws_rx.next().await {
+    let msg = match result {
+      Ok(msg) => msg,
+      Err(e) => {
+        report_protocol_err(e.to_string(), session.scanner_id.as_deref());
+        break;
+      }
+    };
+
+    if msg.is_close() {
+      break;
+    }
+
+    touch(&connections, &session).await;
+
+    if msg.is_pong() {
+      record_ping(&connections, &session, msg.as_bytes()).await;
+      continue;
+    }
+
+    // pings are answered by warp
+    match decode(&msg) {
+      Some(Ok((encoding, device_message))) => {
+        // we answer in whatever encoding the scanner started up with, if its protocol version has it
+        if let DeviceMessage::Startup { protocol_version, .. } = &device_message {
+          if encoding == Encoding::Cbor
+            && protocol_version.unwrap_or(1) < CapabilityKind::Cbor.protocol_version()
+          {
+            report_protocol_err(
+              "CBOR startup at a protocol version without CBOR".to_owned(),
+              session.scanner_id.as_deref(),
+            );
+            let _ = encoding_tx.send(Encoding::Json);
+            let _ = session.sender.send(ServerMessage::StartupFail);
+            continue;
+          }
+          let _ = encoding_tx.send(encoding);
+        }
+        handle_message(&config, &db, &connections, &feed, &mut session, device_message).await
+      }
+      Some(Err(e)) => report_protocol_err(e, session.scanner_id.as_deref()),
+      None => (),
+    }
+  }
+
+  // remove ourselves from the registry, unless a newer session already replaced us
+  if let Some(scanner_id) = session.scanner_id {
+    let removed = {
+      let mut connections = connections.lock().await;
+      if connections
+        .get(&scanner_id)
+        .map_or(false, |x| x.connection_id == session.connection_id)
+      {
+        connections.remove(&scanner_id)
+      } else {
+        None
+      }
+    };
+
+    if let Some(connection) = removed {
+      // lets the command delivery loop notice that we're gone
+      connection.wakeup.notify_one();
+
+      if let Err(e) = scanner_presence_service::add(&mut *db.lock().await, scanner_id, false, None).await {
+        report_postgres_err(e);
+      }
+    }
+  }
+}
+
+// records that the scanner is still alive
+async fn touch(connections: &Connections, session: &Session) {
+  if let Some(scanner_id) = &session.scanner_id {
+    if let Some(connection) = connections.lock().await.get_mut(scanner_id) {
+      if connection.connection_id == session.connection_id {
+        connection.last_seen_time = utils::current_time_millis();
+      }
+    }
+  }
+}
+
+// the pong carries back the time we sent the ping
+async fn record_ping(connections: &Connections, session: &Session, payload: &[u8]) {
+  let sent_time = match payload.try_into() {
+    Ok(bytes) => i64::from_be_bytes(bytes),
+    Err(_) => return,
+  };
+
+  if let Some(scanner_id) = &session.scanner_id {
+    if let Some(connection) = connections.lock().await.get_mut(scanner_id) {
+      if connection.connection_id == session.connection_id {
+        if connection.ping_samples.len() == PING_SAMPLES {
+          connection.ping_samples.pop_front();
+        }
+        connection
+          .ping_samples
+          .push_back(utils::current_time_millis() - sent_time);
+      }
+    }
+  }
+}
+
+async fn handle_message(
+  config: &Config,
+  db: &Db,
+  connections: &Connections,
+  feed: &Feed,
+  session: &mut Session,
+  device_message: DeviceMessage,
+) {
+  // drop messages the scanner said it wouldn't send
+  if !device_message.allowed(session.protocol_version) {
+    report_protocol_err(
+      format!(
+        "{:?} is not supported by protocol version {}",
+        device_message.capability(),
+        session.protocol_version
+      ),
+      session.scanner_id.as_deref(),
+    );
+    return;
+  }
+
+  let reply = match device_message {
+    DeviceMessage::Startup {
+      uid,
+      protocol_version,
+      firmware_version,
+      api_key,
+      read_epoch,
+    } => {
+      // A socket belongs to the first scanner that started up on it. Letting another uid take it over
+      // would strand that scanner's connection in the registry, and never record it going offline.
+      if session.scanner_id.as_deref().map_or(false, |x| x != uid) {
+        report_protocol_err(
+          format!("startup as {} on a session that already started", uid),
+          session.scanner_id.as_deref(),
+        );
+        Some(ServerMessage::StartupFail)
+      } else {
+        // speak the newest version we both know
+        session.protocol_version = protocol_version
+          .unwrap_or(1)
+          .clamp(1, device_protocol::PROTOCOL_VERSION);
+        session.firmware_version = firmware_version;
+        session.read_epoch = read_epoch;
+
+        challenge_or_startup(config, db, connections, session, uid, api_key).await
+      }
+    }
+    DeviceMessage::ChallengeResponse { response } => match session.challenge.take() {
+      Some(challenge) => {
+        if verify_challenge(&challenge, &response) {
+          startup_session(
+            config,
+            db,
+            connections,
+            session,
+            challenge.uid,
+            challenge.api_key,
+            true,
+          )
+          .await
+        } else {
+          report_auth_fail("wrong challenge response".to_owned(), &challenge.uid);
+          Some(ServerMessage::StartupFail)
+        }
+      }
+      None => {
+        report_protocol_err(
+          "challenge response without a challenge".to_owned(),
+          session.scanner_id.as_deref(),
+        );
+        Some(ServerMessage::StartupFail)
+      }
+    },
+    DeviceMessage::CardRead {
+      card_read_id,
+      card_payload,
+      device_time,
+    } => match session.scanner_id.clone() {
+      Some(scanner_id) => {
+        // waiting on the feed takes a while, so don't hold up the rest of the session
+        let db = db.clone();
+        let feed = feed.clone();
+        let sender = session.sender.clone();
+        let read_epoch = session.read_epoch;
+        tokio::spawn(async move {
+          let sound = card_read(
+            &db,
+            &feed,
+            scanner_id,
+            read_epoch,
+            card_read_id,
+            card_payload,
+            device_time,
+          )
+          .await
+          .unwrap_or(SoundKind::Error);
+          let _ = sender.send(ServerMessage::CardReadAck {
+            card_read_id,
+            sound,
+          });
+        });
+        None
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    // unacked reads are kept by the scanner and sent again in its next batch
+    DeviceMessage::CardReadBatch { card_reads } => match session.scanner_id.clone() {
+      Some(scanner_id) => card_read_batch(db, feed, scanner_id, session.read_epoch, card_reads)
+        .await
+        .ok()
+        .map(|card_read_ids| ServerMessage::CardReadBatchAck { card_read_ids }),
+      None => Some(ServerMessage::NoStartup),
+    },
+    // acks aren't answered
+    DeviceMessage::CommandAck { command_id } => match &session.scanner_id {
+      Some(scanner_id) => {
+        if command_ack(db, scanner_id, command_id).await.is_ok() {
+          command_dispatcher::resolve_ack(connections, scanner_id, command_id).await;
+        }
+        None
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    DeviceMessage::FirmwareChunkRequest {
+      command_id,
+      offset,
+      length,
+    } => match &session.scanner_id {
+      Some(scanner_id) => {
+        firmware_transfer::chunk(
+          db,
+          scanner_id,
+          &mut session.firmware_transfers,
+          command_id,
+          offset,
+          length,
+        )
+        .await
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    DeviceMessage::FirmwareVerify { command_id, sha256 } => match &session.scanner_id {
+      Some(scanner_id) => {
+        let firmware_version = session.firmware_version.clone();
+        firmware_transfer::verify(db, scanner_id, firmware_version, command_id, sha256).await
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    // telemetry isn't answered
+    DeviceMessage::Telemetry {
+      uptime,
+      free_heap,
+      wifi_rssi,
+      temperature,
+      reader_errors,
+    } => match session.scanner_id.clone() {
+      Some(scanner_id) => {
+        if !rate_limited(&mut session.telemetry_rate_limit, "TELEMETRY", &scanner_id) {
+          if let Err(e) = telemetry_service::add(
+            &mut *db.lock().await,
+            scanner_id,
+            uptime,
+            free_heap,
+            wifi_rssi,
+            temperature,
+            reader_errors,
+          )
+          .await
+          {
+            report_postgres_err(e);
+          }
+        }
+        None
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    // logs aren't answered
+    DeviceMessage::Log { severity, msg } => match session.scanner_id.clone() {
+      Some(scanner_id) => {
+        if !rate_limited(&mut session.log_rate_limit, "LOG", &scanner_id) {
+          if let Err(e) =
+            scanner_log_service::add(&mut *db.lock().await, scanner_id, severity, truncate_log(msg)).await
+          {
+            report_postgres_err(e);
+          }
+        }
+        None
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    DeviceMessage::DiagnosticsPart {
+      command_id,
+      part_index,
+      part_count,
+      data,
+    } => match &session.scanner_id {
+      // parts that weren't stored aren't acked, so the scanner sends them again
+      Some(scanner_id) => {
+        diagnostics_upload::part(db, scanner_id, command_id, part_index, part_count, data)
+          .await
+          .ok()
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    // acks aren't answered
+    DeviceMessage::ConfigAck { config_version } => match &session.scanner_id {
+      Some(scanner_id) => {
+        let _ = config_ack(db, scanner_id, config_version).await;
+        None
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+    // acks aren't answered
+    DeviceMessage::RotateKeyAck => match &session.scanner_id {
+      Some(scanner_id) => {
+        rotate_key_ack(db, connections, session, scanner_id).await;
+        None
+      }
+      None => Some(ServerMessage::NoStartup),
+    },
+  };
+
+  if let Some(reply) = reply {
+    // if this fails, the writer task has already stopped and the socket is closing
+    let _ = session.sender.send(reply);
+  }
+}
+
+// a scanner stuck in a loop shouldn't be able to fill up the database
+fn rate_limited(rate_limit: &mut RateLimit, kind: &str, scanner_id: &str) -> bool {
+  if rate_limit.allow(utils::current_time_millis()) {
+    return false;
+  }
+  if rate_limit.just_exceeded() {
+    report_protocol_err(
+      format!("too many {} messages, dropping the rest for a minute", kind),
+      Some(scanner_id),
+    );
+  }
+  true
+}
+
+fn truncate_log(mut msg: String) -> String {
+  if let Some((i, _)) = msg.char_indices().nth(MAX_LOG_LENGTH) {
+    msg.truncate(i);
+  }
+  msg
+}
+
+// scanners with a factory key must answer a CHALLENGE before they are started up
+// scanners without one predate factory keys, and are only trusted by uid if allow_legacy_scanners is set
+async fn challenge_or_startup(
+  config: &Config,
+  db: &Db,
+  connections: &Connections,
+  session: &mut Session,
+  uid: String,
+  api_key: Option<String>,
+) -> Option<ServerMessage> {
+  let factory_key = match factory_key_service::get_by_uid(&mut *db.lock().await, &uid).await {
+    Ok(factory_key) => factory_key,
+    Err(e) => {
+      report_postgres_err(e);
+      return Some(ServerMessage::StartupFail);
+    }
+  };
+
+  let factory_key = match factory_key {
+    Some(factory_key) => factory_key,
+    None if config.allow_legacy_scanners => {
+      report_legacy_startup(&uid);
+      return startup_session(config, db, connections, session, uid, api_key, false).await;
+    }
+    None => {
+      report_auth_fail("startup without a factory key".to_owned(), &uid);
+      return Some(ServerMessage::StartupFail);
+    }
+  };
+
+  // otherwise an attacker could skip the challenge by claiming to be old firmware
+  if session.protocol_version < CapabilityKind::Challenge.protocol_version() {
+    report_auth_fail(
+      format!("startup at protocol version {}, which has no challenge", session.protocol_version),
+      &uid,
+    );
+    return Some(ServerMessage::StartupFail);
+  }
+
+  let nonce = utils::gen_random_string();
+
+  session.challenge = Some(Challenge {
+    uid,
+    api_key,
+    secret_key: factory_key.secret_key,
+    nonce: nonce.clone(),
+  });
+
+  Some(ServerMessage::Challenge { nonce })
+}
+
+// answers STARTUP, then starts delivering the scanner's queued commands
+// challenged is whether the scanner proved it holds its factory secret
+async fn startup_session(
+  config: &Config,
+  db: &Db,
+  connections: &Connections,
+  session: &mut Session,
+  uid: String,
+  api_key: Option<String>,
+  challenged: bool,
+) -> Option<ServerMessage> {
+  // a repeated STARTUP on the same socket, always for the same scanner, already has a delivery loop running
+  let restarted = session.scanner_id.is_some();
+
+  let (api_key, scanner_key) = match startup(db, connections, session, uid, api_key, challenged).await {
+    Ok(v) => v,
+    Err(()) => return Some(ServerMessage::StartupFail),
+  };
+
+  // scanners that predate versioning wouldn't understand the extra fields
+  let versioned = session.protocol_version > 1;
+  let clock_sync = session.protocol_version >= CapabilityKind::ClockSync.protocol_version();
+
+  // the scanner has to know it's started before it gets any commands
+  let _ = session.sender.send(ServerMessage::StartupSuccess {
+    api_key,
+    protocol_version: Some(session.protocol_version).filter(|_| versioned),
+    capabilities: Some(device_protocol::capabilities(session.protocol_version)).filter(|_| versioned),
+    server_time: Some(utils::current_time_millis()).filter(|_| clock_sync),
+  });
+
+  if let (false, Some(scanner_id)) = (restarted, session.scanner_id.clone()) {
+    tokio::spawn(command_dispatcher::run(
+      config.clone(),
+      db.clone(),
+      connections.clone(),
+      scanner_id,
+      session.connection_id,
+      session.wakeup.clone(),
+    ));
+  }
+
+  let con = &mut *db.lock().await;
+
+  // the config may have changed while the scanner was offline
+  if let Err(e) = push_config(con, connections, &scanner_key.scanner_id).await {
+    report_postgres_err(e);
+  }
+
+  // replace keys well before they expire, so the scanner is never locked out
+  if scanner_key.creation_time + scanner_key.duration / 2 < utils::current_time_millis() {
+    if let Err(e) = rotate_key(con, connections, &scanner_key.scanner_id).await {
+      report_postgres_err(e);
+    }
+  }
+
+  None
+}
+
+// on success, returns the scanner's api key
+async fn startup(
+  db: &Db,
+  connections: &Connections,
+  session: &mut Session,
+  uid: String,
+  api_key: Option<String>,
+  challenged: bool,
+) -> Result<(String, ScannerKey), ()> {
+  let con = &mut *db.lock().await;
+
+  let scanner = scanner_service::get_by_scanner_id(con, &uid)
+    .await
+    .map_err(report_postgres_err)?
+    .ok_or_else(|| report_protocol_err(format!("startup from unregistered scanner {}", uid), None))?;
+
+  // deactivated scanners may not connect
+  let scanner_active = scanner_data_service::get_by_scanner_id(con, &scanner.scanner_id)
+    .await
+    .map_err(report_postgres_err)?
+    .map_or(false, |x| x.active);
+
+  if !scanner_active {
+    report_protocol_err(format!("startup from inactive scanner {}", uid), None);
+    return Err(());
+  }
+
+  let (api_key, scanner_key) = match api_key {
+    // the scanner is reconnecting with a key we gave it before
+    Some(api_key) => {
+      let scanner_key = check_key(con, &scanner.scanner_id, &api_key).await?;
+      (api_key, scanner_key)
+    }
+    // Only a scanner that has never been given a key, or that just answered the challenge,
+    // gets a new one. Otherwise anyone who knows the uid could take over the scanner.
+    None => {
+      if !challenged
+        && scanner_key_service::exists_live_by_scanner_id(con, &scanner.scanner_id)
+          .await
+          .map_err(report_postgres_err)?
+      {
+        report_auth_fail("startup without a key, though the scanner has one".to_owned(), &uid);
+        return Err(());
+      }
+
+      issue_key(con, scanner.scanner_id.clone())
+        .await
+        .map_err(report_postgres_err)?
+    }
+  };
+
+  // the scanner started up with this key, so any other key it was sent was either replaced or lost
+  scanner_key_revocation_service::revoke_all_except(
+    con,
+    &scanner.scanner_id,
+    scanner_key.scanner_key_id,
+  )
+  .await
+  .map_err(report_postgres_err)?;
+
+  // make the scanner reachable by the rest of the server
+  let previous = connections.lock().await.insert(
+    scanner.scanner_id.clone(),
+    Connection {
+      connection_id: session.connection_id,
+      last_seen_time: utils::current_time_millis(),
+      protocol_version: session.protocol_version,
+      firmware_version: session.firmware_version.clone(),
+      ping_samples: VecDeque::new(),
+      sender: session.sender.clone(),
+      pending_acks: HashMap::new(),
+      wakeup: session.wakeup.clone(),
+      scanner_key_id: scanner_key.scanner_key_id,
+      pending_key_id: None,
+    },
+  );
+
+  // If we replaced a session, the scanner never went offline,
+  // though it may have restarted into firmware that speaks another protocol version.
+  let record_presence = previous
+    .as_ref()
+    .map_or(true, |x| x.protocol_version != session.protocol_version);
+
+  if record_presence {
+    let added = scanner_presence_service::add(
+      con,
+      scanner.scanner_id.clone(),
+      true,
+      Some(session.protocol_version),
+    )
+    .await;
+
+    // Without session.scanner_id, the disconnect path would never remove our entry or record
+    // the scanner going offline, so the registry has to go back to how it was.
+    if let Err(e) = added {
+      let mut connections = connections.lock().await;
+      match previous {
+        Some(previous) => connections.insert(scanner.scanner_id.clone(), previous),
+        None => connections.remove(&scanner.scanner_id),
+      };
+      report_postgres_err(e);
+      return Err(());
+    }
+  }
+
+  // a quick reconnect replaced another session, whose delivery loop has to notice that it's gone
+  if let Some(previous) = previous.filter(|x| x.connection_id != session.connection_id) {
+    previous.wakeup.notify_one();
+  }
+
+  // see whether a firmware update took
+  if let Err(e) =
+    firmware_transfer::startup(con, &scanner.scanner_id, session.firmware_version.as_deref()).await
+  {
+    report_postgres_err(e);
+  }
+
+  session.scanner_id = Some(scanner.scanner_id);
+
+  Ok((api_key, scanner_key))
+}
+
+// the key must belong to the scanner, and be neither expired nor revoked
+async fn check_key(
+  con: &mut tokio_postgres::Client,
+  scanner_id: &str,
+  api_key: &str,
+) -> Result<ScannerKey, ()> {
+  let scanner_key = scanner_key_service::get_by_scanner_key_hash(con, &utils::hash_str(api_key))
+    .await
+    .map_err(report_postgres_err)?
+    .filter(|x| x.scanner_id == scanner_id)
+    .ok_or_else(|| report_auth_fail("startup with an unknown key".to_owned(), scanner_id))?;
+
+  if scanner_key.creation_time + scanner_key.duration < utils::current_time_millis() {
+    report_auth_fail("startup with an expired key".to_owned(), scanner_id);
+    return Err(());
+  }
+
+  if scanner_key_revocation_service::get_by_scanner_key_id(con, scanner_key.scanner_key_id)
+    .await
+    .map_err(report_postgres_err)?
+    .is_some()
+  {
+    report_auth_fail("startup with a revoked key".to_owned(), scanner_id);
+    return Err(());
+  }
+
+  let last_used_time = scanner_key_service::touch(con, scanner_key.scanner_key_id)
+    .await
+    .map_err(report_postgres_err)?;
+
+  Ok(ScannerKey {
+    last_used_time,
+    ..scanner_key
+  })
+}
+
+// returns the raw key along with what was stored
+async fn issue_key(
+  con: &mut tokio_postgres::Client,
+  scanner_id: String,
+) -> Result<(String, ScannerKey), tokio_postgres::Error> {
+  let raw_api_key = utils::gen_random_string();
+
+  let scanner_key =
+    scanner_key_service::add(con, scanner_id, utils::hash_str(&raw_api_key), KEY_DURATION).await?;
+
+  Ok((raw_api_key, scanner_key))
+}
+
+// sends the scanner a new key. The old ones stay valid until the scanner acks it.
+// returns None if the scanner isn't connected with a protocol version that can rotate keys
+pub async fn rotate_key(
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  scanner_id: &str,
+) -> Result<Option<ScannerKey>, tokio_postgres::Error> {
+  let can_rotate = connections.lock().await.get(scanner_id).map_or(false, |x| {
+    x.protocol_version >= CapabilityKind::KeyRotation.protocol_version()
+  });
+
+  if !can_rotate {
+    return Ok(None);
+  }
+
+  let (raw_api_key, scanner_key) = issue_key(con, scanner_id.to_owned()).await?;
+
+  // the scanner may have dropped while the key was being issued
+  match connections.lock().await.get_mut(scanner_id) {
+    Some(connection) => {
+      connection.pending_key_id = Some(scanner_key.scanner_key_id);
+      let _ = connection.sender.send(ServerMessage::RotateKey {
+        api_key: raw_api_key,
+      });
+      Ok(Some(scanner_key))
+    }
+    None => Ok(None),
+  }
+}
+
+// sends the scanner its most recent config
+// returns whether the scanner is connected with a protocol version that takes configs
+pub async fn push_config(
+  con: &mut tokio_postgres::Client,
+  connections: &Connections,
+  scanner_id: &str,
+) -> Result<bool, tokio_postgres::Error> {
+  let scanner_config = match scanner_config_service::get_by_scanner_id(con, scanner_id).await? {
+    Some(scanner_config) => scanner_config,
+    // scanners that were never configured keep their defaults
+    None => return Ok(false),
+  };
+
+  match connections.lock().await.get(scanner_id) {
+    Some(connection)
+      if connection.protocol_version >= CapabilityKind::Config.protocol_version() =>
+    {
+      let _ = connection.sender.send(ServerMessage::Config {
+        config_version: scanner_config.scanner_config_id,
+        beep_volume: scanner_config.beep_volume,
+        led_brightness: scanner_config.led_brightness,
+        read_debounce: scanner_config.read_debounce,
+      });
+      Ok(true)
+    }
+    _ => Ok(false),
+  }
+}
+
+async fn config_ack(db: &Db, scanner_id: &str, config_version: i64) -> Result<(), ()> {
+  let con = &mut *db.lock().await;
+
+  // scanners may only ack their own configs
+  scanner_config_service::get_by_scanner_config_id(con, config_version)
+    .await
+    .map_err(report_postgres_err)?
+    .filter(|x| x.scanner_id == scanner_id)
+    .ok_or_else(|| {
+      report_protocol_err(
+        format!("ack for config {} of another scanner", config_version),
+        Some(scanner_id),
+      )
+    })?;
+
+  scanner_config_ack_service::add(con, config_version)
+    .await
+    .map_err(report_postgres_err)?;
+
+  Ok(())
+}
+
+async fn rotate_key_ack(db: &Db, connections: &Connections, session: &Session, scanner_id: &str) {
+  let pending_key_id = match connections.lock().await.get_mut(scanner_id) {
+    Some(connection) if connection.connection_id == session.connection_id => {
+      let pending_key_id = connection.pending_key_id.take();
+      if let Some(scanner_key_id) = pending_key_id {
+        connection.scanner_key_id = scanner_key_id;
+      }
+      pending_key_id
+    }
+    _ => None,
+  };
+
+  match pending_key_id {
+    Some(scanner_key_id) => {
+      if let Err(e) = scanner_key_revocation_service::revoke_all_except(
+        &mut *db.lock().await,
+        scanner_id,
+        scanner_key_id,
+      )
+      .await
+      {
+        report_postgres_err(e);
+      }
+    }
+    None => report_protocol_err("ack for a key that was never sent".to_owned(), Some(scanner_id)),
+  }
+}
+
+// a device time too far in the future, or too far in the past to have been kept offline,
+// means the scanner's clock can't be trusted
+fn clock_drift_suspect(device_time: Option<i64>, server_time: i64) -> bool {
+  match device_time {
+    Some(device_time) => {
+      device_time > server_time + MAX_CLOCK_AHEAD || device_time < server_time - MAX_OFFLINE_AGE
+    }
+    None => false,
+  }
+}
+
+// A FULL_RESET restarts the scanner's cardReadId counter, so ids are only unique within an epoch.
+// Scanners say which epoch they are in at STARTUP. For older ones that don't, the best guess is how many
+// FULL_RESETs they acked, which is off whenever an ack is lost or comes in after the scanner restarted.
+async fn card_read_epoch(
+  con: &mut tokio_postgres::Client,
+  scanner_id: &str,
+  read_epoch: Option<i64>,
+) -> Result<i64, ()> {
+  match read_epoch {
+    Some(read_epoch) => Ok(read_epoch),
+    None => command_service::count_acked_by_scanner_id_and_command_kind(
+      con,
+      scanner_id,
+      CommandKind::FullReset,
+    )
+    .await
+    .map_err(report_postgres_err),
+  }
+}
+
+// Whether a stored read with the same cardReadId is really this read, resent by the scanner.
+// Without a readEpoch the epoch is only a guess, so the read itself has to match too.
+fn same_read(
+  stored: &CardRead,
+  read_epoch: Option<i64>,
+  card_payload: &[u8],
+  device_time: Option<i64>,
+) -> bool {
+  read_epoch.is_some() || (stored.card_payload == card_payload && stored.device_time == device_time)
+}
+
+// The earlier read of the same card, if this one, read at read_time, came within the scanner's debounce window of it.
+// Offline reads reach the server long after they happen, so they are compared by when the scanner read them.
+async fn debounced_by(
+  con: &mut impl GenericClient,
+  scanner_id: &str,
+  card_payload: &[u8],
+  read_time: i64,
+) -> Result<Option<CardRead>, ()> {
+  let read_debounce = scanner_config_service::get_by_scanner_id(con, scanner_id)
+    .await
+    .map_err(report_postgres_err)?
+    .map_or(DEFAULT_READ_DEBOUNCE, |x| x.read_debounce);
+
+  let previous = card_read_service::get_latest_forwarded_by_card_payload(con, scanner_id, card_payload)
+    .await
+    .map_err(report_postgres_err)?;
+
+  Ok(previous.filter(|x| {
+    let previous_time = match (x.offline, x.device_time) {
+      (true, Some(device_time)) => device_time,
+      _ => x.creation_time,
+    };
+    (read_time - previous_time).abs() < read_debounce
+  }))
+}
+
+// how a live card read gets its sound
+enum Decision {
+  Decided(SoundKind),
+  // repeats another read that is still waiting on the listeners, and sets its own row's sound once that's decided
+  Repeat(Option<i64>, i64, oneshot::Receiver<SoundKind>),
+  Forward(CardRead),
+}
+
+// how a read that repeats an earlier one gets its sound
+enum Follow {
+  Decided(SoundKind),
+  Waiting(oneshot::Receiver<SoundKind>),
+}
+
+// Follows the sound of an earlier read. Returns None if the read should be forwarded after all,
+// because nobody answered the earlier one or nobody is deciding it any more.
+async fn follow(feed: &Feed, previous: &CardRead) -> Option<Follow> {
+  match (previous.offline, &previous.sound_kind) {
+    // a read that already came in a batch never gets a sound, so it's only acked
+    (true, _) => Some(Follow::Decided(SoundKind::Ack)),
+    (false, Some(SoundKind::TimedOut)) => None,
+    (false, Some(sound)) => Some(Follow::Decided(sound.clone())),
+    // still waiting on the listeners, unless the server restarted or failed to store the sound
+    (false, None) => feed_socket::expect_sound(feed, previous.card_read_id)
+      .await
+      .map(Follow::Waiting),
+  }
+}
+
+// records the sound, then lets any repeats of the read know about it
+async fn decide(db: &Db, feed: &Feed, card_read_id: i64, sound: SoundKind) -> Result<(), ()> {
+  let result = card_read_service::set_sound_kind(&mut *db.lock().await, card_read_id, sound.clone())
+    .await
+    .map_err(report_postgres_err);
+
+  feed_socket::resolve_sound(feed, card_read_id, sound).await;
+
+  result
+}
+
+// on success, returns the sound that the scanner should play
+async fn card_read(
+  db: &Db,
+  feed: &Feed,
+  scanner_id: String,
+  read_epoch: Option<i64>,
+  card_read_id: i64,
+  card_payload: Vec<u8>,
+  device_time: Option<i64>,
+) -> Result<SoundKind, ()> {
+  let decision = {
+    let con = &mut *db.lock().await;
+
+    let card_read_epoch = card_read_epoch(con, &scanner_id, read_epoch).await?;
+
+    // the scanner resends reads whose ack it lost, these get the sound that was decided the first time
+    if let Some(card_read) =
+      card_read_service::get_by_device_card_read_id(con, &scanner_id, card_read_epoch, card_read_id)
+        .await
+        .map_err(report_postgres_err)?
+        .filter(|x| same_read(x, read_epoch, &card_payload, device_time))
+    {
+      match follow(feed, &card_read).await {
+        Some(Follow::Decided(sound)) => Decision::Decided(sound),
+        // the first copy is still waiting on the listeners, and sets its own sound
+        Some(Follow::Waiting(receiver)) => Decision::Repeat(None, card_read.card_read_id, receiver),
+        None => {
+          feed_socket::start_deciding(feed, card_read.card_read_id).await;
+          Decision::Forward(card_read)
+        }
+      }
+    } else {
+      let previous =
+        debounced_by(con, &scanner_id, &card_payload, utils::current_time_millis()).await?;
+
+      let follow = match &previous {
+        Some(previous) => follow(feed, previous).await,
+        None => None,
+      };
+
+      // students often tap twice in a row, which shouldn't sign them in and straight back out
+      let card_read = card_read_service::add(
+        con,
+        scanner_id.clone(),
+        card_read_epoch,
+        card_read_id,
+        card_payload,
+        device_time,
+        clock_drift_suspect(device_time, utils::current_time_millis()),
+        previous.as_ref().filter(|_| follow.is_some()).map(|x| x.card_read_id),
+        false,
+      )
+      .await
+      .map_err(report_postgres_err)?;
+
+      match (previous, follow) {
+        (_, Some(Follow::Decided(sound))) => {
+          card_read_service::set_sound_kind(con, card_read.card_read_id, sound.clone())
+            .await
+            .map_err(report_postgres_err)?;
+          Decision::Decided(sound)
+        }
+        // the previous read is still waiting on the listeners, so this one plays whatever it does
+        (Some(previous), Some(Follow::Waiting(receiver))) => {
+          Decision::Repeat(Some(card_read.card_read_id), previous.card_read_id, receiver)
+        }
+        _ => {
+          feed_socket::start_deciding(feed, card_read.card_read_id).await;
+          Decision::Forward(card_read)
+        }
+      }
+    }
+  };
+
+  // the db must not stay locked while the listeners decide
+  match decision {
+    Decision::Decided(sound) => Ok(sound),
+    Decision::Repeat(repeat_card_read_id, repeated_card_read_id, receiver) => {
+      let sound = feed_socket::wait_for_sound(feed, repeated_card_read_id, receiver).await;
+      if let Some(repeat_card_read_id) = repeat_card_read_id {
+        decide(db, feed, repeat_card_read_id, sound.clone()).await?;
+      }
+      Ok(sound)
+    }
+    Decision::Forward(card_read) => {
+      // listeners get the server's id, which unlike the scanner's is never reused
+      let sound = feed_socket::forward_card_read(
+        feed,
+        scanner_id,
+        card_read.card_read_id,
+        card_read.card_payload,
+      )
+      .await;
+      decide(db, feed, card_read.card_read_id, sound.clone()).await?;
+      Ok(sound)
+    }
+  }
+}
+
+// on success, returns the ids of the stored card reads
+async fn card_read_batch(
+  db: &Db,
+  feed: &Feed,
+  scanner_id: String,
+  read_epoch: Option<i64>,
+  card_reads: Vec<OfflineCardRead>,
+) -> Result<Vec<i64>, ()> {
+  let mut card_read_ids = vec![];
+  let mut new_card_reads = vec![];
+  {
+    let con = &mut *db.lock().await;
+
+    let card_read_epoch = card_read_epoch(con, &scanner_id, read_epoch).await?;
+
+    // either the whole batch is stored or the scanner sends it again
+    let mut sp = con.transaction().await.map_err(report_postgres_err)?;
+
+    for x in card_reads {
+      card_read_ids.push(x.card_read_id);
+
+      // reads from a batch whose ack was lost are already stored and forwarded
+      if card_read_service::get_by_device_card_read_id(
+        &mut sp,
+        &scanner_id,
+        card_read_epoch,
+        x.card_read_id,
+      )
+      .await
+      .map_err(report_postgres_err)?
+      .filter(|stored| same_read(stored, read_epoch, &x.card_payload, Some(x.device_time)))
+      .is_some()
+      {
+        continue;
+      }
+
+      let suspect = clock_drift_suspect(Some(x.device_time), utils::current_time_millis());
+      if suspect {
+        report_protocol_err(
+          format!("implausible device time {} for card read {}", x.device_time, x.card_read_id),
+          Some(&scanner_id),
+        );
+      }
+
+      // repeat taps while offline are stored but not forwarded, like live ones.
+      // A clock that can't be trusted can't tell them apart though.
+      let previous = if suspect {
+        None
+      } else {
+        debounced_by(&mut sp, &scanner_id, &x.card_payload, x.device_time).await?
+      };
+
+      let card_read = card_read_service::add(
+        &mut sp,
+        scanner_id.clone(),
+        card_read_epoch,
+        x.card_read_id,
+        x.card_payload.clone(),
+        Some(x.device_time),
+        suspect,
+        previous.as_ref().map(|previous| previous.card_read_id),
+        true,
+      )
+      .await
+      .map_err(report_postgres_err)?;
+
+      if previous.is_none() {
+        new_card_reads.push((card_read.card_read_id, x));
+      }
+    }
+
+    sp.commit().await.map_err(report_postgres_err)?;
+  }
+
+  // the sound was played long ago, so the listeners only need to hear about it
+  for (card_read_id, x) in new_card_reads {
+    feed_socket::forward_offline_card_read(
+      feed,
+      scanner_id.clone(),
+      card_read_id,
+      x.card_payload,
+      x.device_time,
+    )
+    .await;
+  }
+
+  Ok(card_read_ids)
+}
+
+async fn command_ack(db: &Db, scanner_id: &str, command_id: i64) -> Result<(), ()> {
+  let con = &mut *db.lock().await;
+
+  let command = command_service::get_by_command_id(con, command_id)
+    .await
+    .map_err(report_postgres_err)?
+    .ok_or_else(|| {
+      report_protocol_err(
+        format!("ack for nonexistent command {}", command_id),
+        Some(scanner_id),
+      )
+    })?;
+
+  // scanners may only ack their own commands
+  if command.scanner_id != scanner_id {
+    report_protocol_err(
+      format!("ack for command {} of another scanner", command_id),
+      Some(scanner_id),
+    );
+    return Err(());
+  }
+
+  // the scanner may ack the same command twice, only the first counts
+  if command_ack_service::get_by_command_id(con, command_id)
+    .await
+    .map_err(report_postgres_err)?
+    .is_none()
+  {
+    command_ack_service::add(con, command_id)
+      .await
+      .map_err(report_postgres_err)?;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rate_limit_drops_messages_over_the_limit() {
+    let mut rate_limit = RateLimit::new(2);
+    assert!(rate_limit.allow(1000));
+    assert!(rate_limit.allow(1001));
+    assert!(!rate_limit.allow(1002));
+    assert!(rate_limit.just_exceeded());
+    assert!(!rate_limit.allow(1003));
+    assert!(!rate_limit.just_exceeded());
+  }
+
+  #[test]
+  fn rate_limit_resets_each_window() {
+    let mut rate_limit = RateLimit::new(1);
+    assert!(rate_limit.allow(1000));
+    assert!(!rate_limit.allow(1000 + RATE_LIMIT_WINDOW - 1));
+    assert!(rate_limit.allow(1000 + RATE_LIMIT_WINDOW));
+  }
+
+  #[test]
+  fn truncate_log_keeps_short_messages() {
+    assert_eq!(truncate_log("reader reset".to_owned()), "reader reset");
+  }
+
+  #[test]
+  fn truncate_log_cuts_on_a_char_boundary() {
+    let msg = "é".repeat(MAX_LOG_LENGTH + 10);
+    let truncated = truncate_log(msg);
+    assert_eq!(truncated.chars().count(), MAX_LOG_LENGTH);
+  }
+
+  static SERVER_TIME: i64 = 1633046400000;
+
+  #[test]
+  fn clock_drift_ignores_reads_without_a_device_time() {
+    assert!(!clock_drift_suspect(None, SERVER_TIME));
+  }
+
+  #[test]
+  fn clock_drift_allows_a_little_ahead() {
+    assert!(!clock_drift_suspect(Some(SERVER_TIME), SERVER_TIME));
+    assert!(!clock_drift_suspect(Some(SERVER_TIME + MAX_CLOCK_AHEAD), SERVER_TIME));
+    assert!(clock_drift_suspect(Some(SERVER_TIME + MAX_CLOCK_AHEAD + 1), SERVER_TIME));
+  }
+
+  #[test]
+  fn clock_drift_allows_reads_kept_offline_for_a_week() {
+    assert!(!clock_drift_suspect(Some(SERVER_TIME - MAX_OFFLINE_AGE), SERVER_TIME));
+    assert!(clock_drift_suspect(Some(SERVER_TIME - MAX_OFFLINE_AGE - 1), SERVER_TIME));
+  }
+
+  fn stored_read(card_payload: Vec<u8>, device_time: Option<i64>) -> CardRead {
+    CardRead {
+      card_read_id: 1,
+      creation_time: 0,
+      scanner_id: "scanner".to_owned(),
+      card_read_epoch: 0,
+      device_card_read_id: 7,
+      card_payload,
+      device_time,
+      clock_drift_suspect: false,
+      sound_kind: None,
+      debounced_card_read_id: None,
+      offline: false,
+    }
+  }
+
+  #[test]
+  fn same_read_trusts_a_read_epoch() {
+    assert!(same_read(&stored_read(vec![1], None), Some(5), &[2], Some(1000)));
+  }
+
+  #[test]
+  fn same_read_compares_legacy_reads() {
+    let stored = stored_read(vec![1], Some(1000));
+    assert!(same_read(&stored, None, &[1], Some(1000)));
+    assert!(!same_read(&stored, None, &[2], Some(1000)));
+    assert!(!same_read(&stored, None, &[1], Some(2000)));
+  }
+}
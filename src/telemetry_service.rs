@@ -0,0 +1,330 @@
+use super::db_types::*;
+use super::utils::current_time_millis;
+use tokio_postgres::GenericClient;
+
+impl From<tokio_postgres::row::Row> for Telemetry {
+  // select * from telemetry order only, otherwise it will fail
+  fn from(row: tokio_postgres::row::Row) -> Telemetry {
+    Telemetry {
+      telemetry_id: row.get("telemetry_id"),
+      creation_time: row.get("creation_time"),
+      scanner_id: row.get("scanner_id"),
+      resolution: row.get("resolution"),
+      sample_count: row.get("sample_count"),
+      uptime: row.get("uptime"),
+      free_heap: row.get("free_heap"),
+      wifi_rssi: row.get("wifi_rssi"),
+      temperature: row.get("temperature"),
+      reader_errors: row.get("reader_errors"),
+    }
+  }
+}
+
+pub async fn add(
+  con: &mut impl GenericClient,
+  scanner_id: String,
+  uptime: i64,
+  free_heap: i64,
+  wifi_rssi: i64,
+  temperature: f64,
+  reader_errors: i64,
+) -> Result<Telemetry, tokio_postgres::Error> {
+  let creation_time = current_time_millis();
+
+  let telemetry_id = con
+    .query_one(
+      "INSERT INTO
+       telemetry_t(
+        creation_time,
+        scanner_id,
+        resolution,
+        sample_count,
+        uptime,
+        free_heap,
+        wifi_rssi,
+        temperature,
+        reader_errors
+       )
+       VALUES($1, $2, 0, 1, $3, $4, $5, $6, $7)
+       RETURNING telemetry_id
+      ",
+      &[
+        &creation_time,
+        &scanner_id,
+        &uptime,
+        &free_heap,
+        &wifi_rssi,
+        &temperature,
+        &reader_errors,
+      ],
+    )
+    .await?
+    .get(0);
+
+  // return telemetry
+  Ok(Telemetry {
+    telemetry_id,
+    creation_time,
+    scanner_id,
+    resolution: 0,
+    sample_count: 1,
+    uptime,
+    free_heap,
+    wifi_rssi,
+    temperature,
+    reader_errors,
+  })
+}
+
+// what has been summed up so far of a scanner's rows in one period
+struct Period {
+  first: Telemetry,
+  sample_count: i64,
+  uptime: i64,
+  free_heap: i64,
+  wifi_rssi: i64,
+  temperature: f64,
+  reader_errors: i64,
+}
+
+impl Period {
+  fn add(&mut self, row: &Telemetry) {
+    self.sample_count += row.sample_count;
+    self.uptime = self.uptime.max(row.uptime);
+    self.free_heap += row.free_heap * row.sample_count;
+    self.wifi_rssi += row.wifi_rssi * row.sample_count;
+    self.temperature += row.temperature * row.sample_count as f64;
+    self.reader_errors += row.reader_errors;
+  }
+
+  fn finish(self, resolution: i64) -> Telemetry {
+    let sample_count = self.sample_count.max(1);
+    Telemetry {
+      creation_time: self.first.creation_time - self.first.creation_time % resolution,
+      resolution,
+      sample_count: self.sample_count,
+      uptime: self.uptime,
+      free_heap: (self.free_heap as f64 / sample_count as f64).round() as i64,
+      wifi_rssi: (self.wifi_rssi as f64 / sample_count as f64).round() as i64,
+      temperature: self.temperature / sample_count as f64,
+      reader_errors: self.reader_errors,
+      ..self.first
+    }
+  }
+}
+
+// Merges the rows into one row per scanner per period of the resolution.
+// Averages are weighted by how many reports went into each row.
+// The rows must be ordered by scanner_id, then creation_time. telemetry_id is only known once stored.
+fn merge(rows: Vec<Telemetry>, resolution: i64) -> Vec<Telemetry> {
+  let mut merged = vec![];
+  let mut current: Option<Period> = None;
+
+  for row in rows {
+    let period_start = row.creation_time - row.creation_time % resolution;
+
+    let same_period = current.as_ref().map_or(false, |period| {
+      period.first.scanner_id == row.scanner_id
+        && period.first.creation_time - period.first.creation_time % resolution == period_start
+    });
+
+    if !same_period {
+      if let Some(period) = current.take() {
+        merged.push(period.finish(resolution));
+      }
+      current = Some(Period {
+        first: Telemetry {
+          telemetry_id: 0,
+          ..row.clone()
+        },
+        sample_count: 0,
+        uptime: 0,
+        free_heap: 0,
+        wifi_rssi: 0,
+        temperature: 0.0,
+        reader_errors: 0,
+      });
+    }
+
+    if let Some(period) = &mut current {
+      period.add(&row);
+    }
+  }
+
+  if let Some(period) = current {
+    merged.push(period.finish(resolution));
+  }
+
+  merged
+}
+
+// Merges the rows finer than the resolution that were made before max_creation_time
+// into one row per scanner per period of the resolution.
+pub async fn downsample(
+  con: &mut impl GenericClient,
+  resolution: i64,
+  max_creation_time: i64,
+) -> Result<u64, tokio_postgres::Error> {
+  // only whole periods are merged, so a period is never merged twice
+  let max_creation_time = max_creation_time - max_creation_time % resolution;
+
+  let rows: Vec<Telemetry> = con
+    .query(
+      "SELECT t.* FROM telemetry_t t
+       WHERE t.resolution < $1
+       AND t.creation_time < $2
+       ORDER BY t.scanner_id, t.creation_time
+      ",
+      &[&resolution, &max_creation_time],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+
+  for telemetry in merge(rows, resolution) {
+    con
+      .execute(
+        "INSERT INTO
+         telemetry_t(
+          creation_time,
+          scanner_id,
+          resolution,
+          sample_count,
+          uptime,
+          free_heap,
+          wifi_rssi,
+          temperature,
+          reader_errors
+         )
+         VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ",
+        &[
+          &telemetry.creation_time,
+          &telemetry.scanner_id,
+          &telemetry.resolution,
+          &telemetry.sample_count,
+          &telemetry.uptime,
+          &telemetry.free_heap,
+          &telemetry.wifi_rssi,
+          &telemetry.temperature,
+          &telemetry.reader_errors,
+        ],
+      )
+      .await?;
+  }
+
+  con
+    .execute(
+      "DELETE FROM telemetry_t WHERE resolution < $1 AND creation_time < $2",
+      &[&resolution, &max_creation_time],
+    )
+    .await
+}
+
+// only returns telemetry from scanners owned by creator_user_id
+pub async fn query(
+  con: &mut impl GenericClient,
+  creator_user_id: i64,
+  props: cnc_service_api::request::TelemetryViewProps,
+) -> Result<Vec<Telemetry>, tokio_postgres::Error> {
+  let results = con
+    .query(
+      "SELECT t.* FROM telemetry_t t
+       INNER JOIN scanner_t s ON s.scanner_id = t.scanner_id
+       WHERE 1 = 1
+       AND ($1::bigint[] IS NULL OR t.telemetry_id = ANY($1))
+       AND ($2::bigint   IS NULL OR t.creation_time >= $2)
+       AND ($3::bigint   IS NULL OR t.creation_time <= $3)
+       AND ($4::text[]   IS NULL OR t.scanner_id = ANY($4))
+       AND ($5::bigint   IS NULL OR t.resolution = $5)
+       AND s.creator_user_id = $6
+       ORDER BY t.scanner_id, t.creation_time
+      ",
+      &[
+        &props.telemetry_id,
+        &props.min_creation_time,
+        &props.max_creation_time,
+        &props.scanner_id,
+        &props.resolution,
+        &creator_user_id,
+      ],
+    )
+    .await?
+    .into_iter()
+    .map(|row| row.into())
+    .collect();
+  Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  static ONE_HOUR: i64 = 60 * 60 * 1000;
+
+  fn report(scanner_id: &str, creation_time: i64, sample_count: i64, free_heap: i64) -> Telemetry {
+    Telemetry {
+      telemetry_id: 1,
+      creation_time,
+      scanner_id: scanner_id.to_owned(),
+      resolution: 0,
+      sample_count,
+      uptime: creation_time / 1000,
+      free_heap,
+      wifi_rssi: -60,
+      temperature: 40.0,
+      reader_errors: 1,
+    }
+  }
+
+  #[test]
+  fn merges_each_period_of_each_scanner() {
+    let merged = merge(
+      vec![
+        report("a", 0, 1, 100),
+        report("a", 10, 1, 200),
+        report("a", ONE_HOUR + 10, 1, 300),
+        report("b", 20, 1, 400),
+      ],
+      ONE_HOUR,
+    );
+    assert_eq!(merged.len(), 3);
+    assert_eq!((merged[0].scanner_id.as_str(), merged[0].creation_time), ("a", 0));
+    assert_eq!((merged[1].scanner_id.as_str(), merged[1].creation_time), ("a", ONE_HOUR));
+    assert_eq!((merged[2].scanner_id.as_str(), merged[2].creation_time), ("b", 0));
+    assert!(merged.iter().all(|x| x.resolution == ONE_HOUR));
+  }
+
+  #[test]
+  fn averages_are_weighted_by_sample_count() {
+    let merged = merge(vec![report("a", 0, 3, 100), report("a", 10, 1, 200)], ONE_HOUR);
+    assert_eq!(merged[0].sample_count, 4);
+    assert_eq!(merged[0].free_heap, 125);
+    assert_eq!(merged[0].wifi_rssi, -60);
+    assert!((merged[0].temperature - 40.0).abs() < 1e-9);
+  }
+
+  #[test]
+  fn errors_are_summed_and_uptime_is_the_most() {
+    let merged = merge(vec![report("a", 5000, 1, 100), report("a", 2000, 1, 100)], ONE_HOUR);
+    assert_eq!(merged[0].reader_errors, 2);
+    assert_eq!(merged[0].uptime, 5);
+  }
+
+  #[test]
+  fn merging_merged_rows_keeps_the_weights() {
+    let hourly = merge(
+      vec![
+        report("a", 0, 1, 100),
+        report("a", ONE_HOUR, 1, 100),
+        report("a", ONE_HOUR + 10, 1, 400),
+      ],
+      ONE_HOUR,
+    );
+    let daily = merge(hourly, 24 * ONE_HOUR);
+    assert_eq!(daily.len(), 1);
+    assert_eq!(daily[0].sample_count, 3);
+    assert_eq!(daily[0].free_heap, 200);
+  }
+}
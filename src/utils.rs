@@ -23,6 +23,10 @@ pub fn hash_str(key: &str) -> String {
   base64_url::encode(&result)
 }
 
+pub fn sha256_hex(data: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(data))
+}
+
 pub fn is_secure_password(password: &str) -> bool {
   let len = password.len();
 